@@ -0,0 +1,120 @@
+use axum::{
+    Json,
+    extract::{Path, State},
+};
+use rquest::StatusCode;
+use serde::Deserialize;
+use tokio::sync::oneshot;
+use tracing::{error, info, warn};
+
+use crate::{
+    config::{CookieInfo, CookieStatus},
+    cookie::{CookieEntry, CookieOp},
+    messages::Auth,
+    state::AppState,
+};
+
+/// One or more cookie strings
+#[derive(Deserialize, Debug)]
+#[serde(untagged)]
+pub enum CookieInput {
+    One(String),
+    Many(Vec<String>),
+}
+
+/// Only the password may manage cookies, API keys are rejected
+fn check_admin(s: &AppState, key: &str) -> Result<(), StatusCode> {
+    if !s.config.is_admin(key) {
+        warn!("Cookie admin API requires the password");
+        return Err(StatusCode::FORBIDDEN);
+    }
+    Ok(())
+}
+
+/// Send an admin operation to the cookie manager and wait for the answer
+async fn ask<T>(
+    s: &AppState,
+    op: impl FnOnce(oneshot::Sender<T>) -> CookieOp,
+) -> Result<T, StatusCode> {
+    let (tx, rx) = oneshot::channel();
+    if let Err(e) = s.admin_tx.send(op(tx)).await {
+        error!("Failed to send cookie admin operation: {}", e);
+        return Err(StatusCode::INTERNAL_SERVER_ERROR);
+    }
+    rx.await.map_err(|e| {
+        error!("Failed to receive cookie admin answer: {}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })
+}
+
+/// Axum handler listing all cookies, masked
+pub async fn api_list_cookies(
+    State(s): State<AppState>,
+    Auth(key): Auth,
+) -> Result<Json<Vec<CookieEntry>>, StatusCode> {
+    check_admin(&s, &key)?;
+    ask(&s, CookieOp::List).await.map(Json)
+}
+
+/// Axum handler adding cookies, nothing is added if any of them is invalid
+pub async fn api_add_cookies(
+    State(s): State<AppState>,
+    Auth(key): Auth,
+    Json(input): Json<CookieInput>,
+) -> StatusCode {
+    if let Err(status) = check_admin(&s, &key) {
+        return status;
+    }
+    if s.config.read_only {
+        warn!("Read-only mode, cookie addition rejected");
+        return StatusCode::FORBIDDEN;
+    }
+    let cookies = match input {
+        CookieInput::One(c) => vec![c],
+        CookieInput::Many(c) => c,
+    };
+    let cookies = cookies
+        .iter()
+        .map(|c| CookieInfo::from(c.as_str()))
+        .collect::<Vec<_>>();
+    if cookies.is_empty() {
+        return StatusCode::BAD_REQUEST;
+    }
+    if let Some(c) = cookies.iter().find(|c| !c.validate()) {
+        warn!("Invalid cookie: {}", c.id());
+        return StatusCode::BAD_REQUEST;
+    }
+    for cookie in cookies {
+        let id = cookie.id();
+        let status = CookieStatus {
+            cookie,
+            ..Default::default()
+        };
+        if let Err(e) = s.submit_tx.send(status).await {
+            error!("Failed to submit cookie: {}", e);
+            return StatusCode::INTERNAL_SERVER_ERROR;
+        }
+        info!("Cookie added: {}", id);
+    }
+    StatusCode::OK
+}
+
+/// Axum handler removing the cookie at an index of the list
+pub async fn api_remove_cookie(
+    State(s): State<AppState>,
+    Auth(key): Auth,
+    Path(index): Path<usize>,
+) -> Result<Json<CookieEntry>, StatusCode> {
+    check_admin(&s, &key)?;
+    if s.config.read_only {
+        warn!("Read-only mode, cookie removal rejected");
+        return Err(StatusCode::FORBIDDEN);
+    }
+    match ask(&s, |tx| CookieOp::Remove(index, tx)).await? {
+        Some(entry) => Ok(Json(entry)),
+        None => {
+            warn!("No cookie at index {}", index);
+            Err(StatusCode::NOT_FOUND)
+        }
+    }
+}
@@ -7,7 +7,6 @@ use crate::{
     config::Reason,
     error::{ClewdrError, check_res_err},
     state::AppState,
-    utils::print_out_json,
 };
 
 impl AppState {
@@ -25,7 +24,7 @@ impl AppState {
             .await?;
         let res = check_res_err(res).await?;
         let bootstrap = res.json::<Value>().await?;
-        print_out_json(&bootstrap, "bootstrap.json");
+        self.log_json(&bootstrap, "bootstrap.json");
         if bootstrap["account"].is_null() {
             return Err(ClewdrError::InvalidCookie(Reason::Null));
         }
@@ -79,7 +78,7 @@ impl AppState {
             .await?;
         let res = check_res_err(res).await?;
         let ret_json = res.json::<Value>().await?;
-        print_out_json(&ret_json, "org.json");
+        self.log_json(&ret_json, "org.json");
         let acc_info = ret_json
             .as_array()
             .and_then(|a| {
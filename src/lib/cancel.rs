@@ -0,0 +1,207 @@
+use axum::{Json, body::Body, extract::State, http::HeaderValue, response::Response};
+use futures::StreamExt;
+use rquest::StatusCode;
+use serde::Deserialize;
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+use tokio::sync::watch;
+use tracing::{info, warn};
+
+use crate::{messages::Auth, state::AppState};
+
+/// Header carrying the id of a request, used to cancel it
+pub const REQUEST_ID_HEADER: &str = "X-Request-Id";
+
+/// Label of the key that sent a request and the signal to cancel it
+type Registration = (String, watch::Sender<bool>);
+
+/// In-flight requests that can be cancelled by id
+#[derive(Clone, Default)]
+pub struct CancelRegistry(Arc<Mutex<HashMap<String, Registration>>>);
+
+impl CancelRegistry {
+    /// Register a request of a key, it can be cancelled until the guard is dropped
+    /// An id already in flight keeps its owner, the new request gets a fresh id
+    pub fn register(&self, id: String, owner: String) -> CancelGuard {
+        let (tx, rx) = watch::channel(false);
+        let mut map = self.0.lock().unwrap();
+        let id = if map.contains_key(&id) {
+            let fresh = uuid::Uuid::new_v4().to_string();
+            warn!("Request id {} is already in flight, using {}", id, fresh);
+            fresh
+        } else {
+            id
+        };
+        map.insert(id.clone(), (owner, tx));
+        drop(map);
+        CancelGuard {
+            registry: self.clone(),
+            id,
+            rx,
+        }
+    }
+
+    /// Cancel the request if it belongs to the owner, None cancels any request
+    /// Returns false if it is unknown or belongs to another key
+    pub fn cancel(&self, id: &str, owner: Option<&str>) -> bool {
+        self.0
+            .lock()
+            .unwrap()
+            .get(id)
+            .filter(|(o, _)| owner.is_none_or(|owner| o == owner))
+            .is_some_and(|(_, tx)| tx.send(true).is_ok())
+    }
+}
+
+/// Registration of an in-flight request
+pub struct CancelGuard {
+    registry: CancelRegistry,
+    id: String,
+    rx: watch::Receiver<bool>,
+}
+
+impl CancelGuard {
+    /// Wait until the request is cancelled
+    pub async fn cancelled(&self) {
+        let mut rx = self.rx.clone();
+        if rx.wait_for(|c| *c).await.is_err() {
+            // never resolve if the registry dropped the sender
+            std::future::pending::<()>().await;
+        }
+    }
+}
+
+impl Drop for CancelGuard {
+    fn drop(&mut self) {
+        self.registry.0.lock().unwrap().remove(&self.id);
+    }
+}
+
+/// Stop the response body when the request is cancelled
+/// The id is sent back in `X-Request-Id`, it differs from the requested one if that was taken
+pub fn cancellable(res: Response, guard: CancelGuard) -> Response {
+    let (mut parts, body) = res.into_parts();
+    if let Ok(id) = HeaderValue::from_str(&guard.id) {
+        parts.headers.insert(REQUEST_ID_HEADER, id);
+    }
+    let body = body
+        .into_data_stream()
+        .take_until(async move { guard.cancelled().await });
+    Response::from_parts(parts, Body::from_stream(body))
+}
+
+/// Request body to cancel a request
+#[derive(Deserialize)]
+pub struct CancelRequest {
+    pub request_id: String,
+}
+
+/// Axum handler to cancel an in-flight request
+/// A key can only cancel its own requests, the admin can cancel any
+pub async fn api_cancel(
+    State(s): State<AppState>,
+    Auth(key): Auth,
+    Json(c): Json<CancelRequest>,
+) -> StatusCode {
+    let owner = if s.config.is_admin(&key) {
+        None
+    } else {
+        Some(s.config.key_label(&key).unwrap_or_default())
+    };
+    // requests of other keys look unknown, so their ids cannot be probed
+    if s.cancels.cancel(&c.request_id, owner.as_deref()) {
+        info!("Request cancelled: {}", c.request_id);
+        StatusCode::OK
+    } else {
+        warn!("Unknown request id: {}", c.request_id);
+        StatusCode::NOT_FOUND
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{convert::Infallible, time::Duration};
+
+    use futures::stream;
+    use tokio::{
+        sync::{mpsc, watch},
+        time::timeout,
+    };
+
+    use super::*;
+    use crate::{config::Config, cookie::PoolStatus};
+
+    fn state() -> AppState {
+        let (req_tx, _) = mpsc::channel(1);
+        let (ret_tx, _) = mpsc::channel(1);
+        let (submit_tx, _) = mpsc::channel(1);
+        let (admin_tx, _) = mpsc::channel(1);
+        let (_, pool) = watch::channel(PoolStatus::default());
+        let mut config = Config::default();
+        config.no_auth = true;
+        AppState::new(config, req_tx, ret_tx, submit_tx, admin_tx, pool)
+    }
+
+    async fn cancel(state: &AppState, id: &str) -> StatusCode {
+        let c = CancelRequest {
+            request_id: id.to_string(),
+        };
+        api_cancel(State(state.clone()), Auth(String::new()), Json(c)).await
+    }
+
+    #[test]
+    fn only_owner_or_admin_cancels() {
+        let registry = CancelRegistry::default();
+        let _guard = registry.register("req".to_string(), "alice".to_string());
+        assert!(!registry.cancel("req", Some("bob")));
+        assert!(!registry.cancel("other", Some("alice")));
+        assert!(registry.cancel("req", Some("alice")));
+        assert!(registry.cancel("req", None));
+    }
+
+    #[test]
+    fn guard_unregisters() {
+        let registry = CancelRegistry::default();
+        drop(registry.register("req".to_string(), "alice".to_string()));
+        assert!(!registry.cancel("req", None));
+    }
+
+    #[test]
+    fn duplicate_id_gets_fresh_id() {
+        let registry = CancelRegistry::default();
+        let first = registry.register("req".to_string(), "alice".to_string());
+        let second = registry.register("req".to_string(), "bob".to_string());
+        assert_eq!(first.id, "req");
+        assert_ne!(second.id, "req");
+        assert!(!registry.cancel("req", Some("bob")));
+        assert!(registry.cancel("req", Some("alice")));
+        assert!(registry.cancel(&second.id, Some("bob")));
+        drop(second);
+        assert!(registry.cancel("req", Some("alice")));
+    }
+
+    #[tokio::test]
+    async fn cancel_stops_stream() {
+        let state = state();
+        assert_eq!(cancel(&state, "req").await, StatusCode::NOT_FOUND);
+
+        let guard = state
+            .cancels
+            .register("req".to_string(), "admin".to_string());
+        let body =
+            stream::once(async { Ok::<_, Infallible>("data: hello\n\n") }).chain(stream::pending());
+        let res = cancellable(Response::new(Body::from_stream(body)), guard);
+        assert_eq!(res.headers()[REQUEST_ID_HEADER], "req");
+        let mut body = res.into_body().into_data_stream();
+        assert_eq!(body.next().await.unwrap().unwrap(), "data: hello\n\n");
+
+        assert_eq!(cancel(&state, "req").await, StatusCode::OK);
+        let end = timeout(Duration::from_secs(1), body.next()).await;
+        assert!(matches!(end, Ok(None)));
+        // the finished request is unregistered
+        drop(body);
+        assert_eq!(cancel(&state, "req").await, StatusCode::NOT_FOUND);
+    }
+}
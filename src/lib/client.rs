@@ -10,7 +10,7 @@ use serde_json::Value;
 use std::sync::LazyLock;
 use tracing::warn;
 
-use crate::{config::ENDPOINT, state::AppState, types::message::ImageSource};
+use crate::{config::ENDPOINT, error::ClewdrError, state::AppState, types::message::ImageSource};
 
 /// The client to be used for requests to the Claude.ai
 /// This client is used for requests that require a specific emulation
@@ -50,25 +50,42 @@ fn header_ref<S: AsRef<str>>(ref_path: S) -> String {
 }
 impl AppState {
     /// Upload images to the Claude.ai
-    pub async fn upload_images(&self, imgs: Vec<ImageSource>) -> Vec<String> {
-        // upload images
-        let fut = imgs
+    /// Fails if an image is not in a format Claude accepts
+    pub async fn upload_images(&self, imgs: Vec<ImageSource>) -> Result<Vec<String>, ClewdrError> {
+        // decode and check the images before uploading any of them
+        let mut files = Vec::with_capacity(imgs.len());
+        for img in imgs {
+            // check if the image is base64
+            if img.type_ != "base64" {
+                warn!("Image type is not base64");
+                break;
+            }
+            // reject oversized images before decoding them
+            let limit = self.config.max_image_bytes;
+            let estimate = img.data.trim_end_matches('=').len() * 3 / 4;
+            if limit > 0 && estimate > limit {
+                return Err(ClewdrError::ImageTooLarge(estimate, limit));
+            }
+            // decode the image
+            let Ok(bytes) = BASE64_STANDARD
+                .decode(img.data.as_bytes())
+                .inspect_err(|e| {
+                    warn!("Failed to decode image: {}", e);
+                })
+            else {
+                break;
+            };
+            if limit > 0 && bytes.len() > limit {
+                return Err(ClewdrError::ImageTooLarge(bytes.len(), limit));
+            }
+            let media_type = media_type(&bytes, &img.media_type)?;
+            files.push((bytes, media_type));
+        }
+        let fut = files
             .into_iter()
-            .map_while(|img| {
-                // check if the image is base64
-                if img.type_ != "base64" {
-                    warn!("Image type is not base64");
-                    return None;
-                }
-                // decode the image
-                let bytes = BASE64_STANDARD
-                    .decode(img.data.as_bytes())
-                    .inspect_err(|e| {
-                        warn!("Failed to decode image: {}", e);
-                    })
-                    .ok()?;
+            .map_while(|(bytes, media_type)| {
                 // choose the file name based on the media type
-                let file_name = match img.media_type.as_str() {
+                let file_name = match media_type {
                     "image/png" => "image.png",
                     "image/jpeg" => "image.jpg",
                     "image/gif" => "image.gif",
@@ -118,10 +135,79 @@ impl AppState {
             .collect::<Vec<_>>();
 
         // collect the results
-        join_all(fut)
+        Ok(join_all(fut)
             .await
             .into_iter()
             .flatten()
-            .collect::<Vec<_>>()
+            .collect::<Vec<_>>())
+    }
+}
+
+/// Detect the media type from the magic bytes
+/// A mismatched declared type is corrected, unsupported formats are rejected
+fn media_type(bytes: &[u8], declared: &str) -> Result<&'static str, ClewdrError> {
+    let detected = match bytes {
+        [0x89, b'P', b'N', b'G', ..] => "image/png",
+        [0xFF, 0xD8, 0xFF, ..] => "image/jpeg",
+        [b'G', b'I', b'F', b'8', ..] => "image/gif",
+        [
+            b'R',
+            b'I',
+            b'F',
+            b'F',
+            _,
+            _,
+            _,
+            _,
+            b'W',
+            b'E',
+            b'B',
+            b'P',
+            ..,
+        ] => "image/webp",
+        [b'%', b'P', b'D', b'F', ..] => "application/pdf",
+        _ => return Err(ClewdrError::UnsupportedMedia(declared.to_string())),
+    };
+    if detected != declared {
+        warn!("Image declared as {} is {}", declared, detected);
+    }
+    Ok(detected)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn png_declared_as_jpeg_is_corrected() {
+        let png = [0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A];
+        assert_eq!(media_type(&png, "image/jpeg").unwrap(), "image/png");
+    }
+
+    #[test]
+    fn supported_types_detected() {
+        assert_eq!(
+            media_type(&[0xFF, 0xD8, 0xFF, 0xE0], "image/jpeg").unwrap(),
+            "image/jpeg"
+        );
+        assert_eq!(media_type(b"GIF89a", "image/gif").unwrap(), "image/gif");
+        assert_eq!(
+            media_type(b"RIFF\0\0\0\0WEBPVP8 ", "image/webp").unwrap(),
+            "image/webp"
+        );
+        assert_eq!(
+            media_type(b"%PDF-1.7", "application/pdf").unwrap(),
+            "application/pdf"
+        );
+    }
+
+    #[test]
+    fn unsupported_type_rejected() {
+        assert!(matches!(
+            media_type(b"BM\0\0\0\0", "image/bmp"),
+            Err(ClewdrError::UnsupportedMedia(t)) if t == "image/bmp"
+        ));
+        assert!(media_type(b"RIFF\0\0\0\0WAVE", "image/webp").is_err());
+        assert!(media_type(&[], "image/png").is_err());
     }
 }
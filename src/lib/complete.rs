@@ -0,0 +1,152 @@
+use axum::{Json, extract::State, http::HeaderMap, response::Response};
+use serde::{Deserialize, Serialize};
+use tracing::Instrument;
+
+use crate::{
+    messages::{Auth, ClientRequestBody, debug_span, handle_messages},
+    state::{ApiFormat, AppState},
+    types::message::{Message, Role, StopReason},
+};
+
+/// Request body of the legacy text completion API
+#[derive(Deserialize, Serialize, Debug)]
+pub struct LegacyRequestBody {
+    pub prompt: String,
+    pub model: String,
+    #[serde(default)]
+    pub max_tokens_to_sample: Option<u64>,
+    #[serde(default)]
+    pub stop_sequences: Vec<String>,
+    #[serde(default)]
+    pub stream: bool,
+    #[serde(default)]
+    pub temperature: f32,
+    #[serde(default)]
+    pub top_p: f32,
+    #[serde(default)]
+    pub top_k: u64,
+}
+
+impl From<LegacyRequestBody> for ClientRequestBody {
+    /// The prompt is sent as a single user message, so it is pasted as is
+    fn from(value: LegacyRequestBody) -> Self {
+        ClientRequestBody {
+            max_tokens: value.max_tokens_to_sample,
+            messages: vec![Message::new_text(Role::User, value.prompt)],
+            stop_sequences: value.stop_sequences,
+            model: value.model,
+            stream: value.stream,
+            thinking: None,
+            system: Default::default(),
+            temperature: value.temperature,
+            top_p: value.top_p,
+            top_k: value.top_k,
+        }
+    }
+}
+
+/// Response of the legacy text completion API, also used as stream event
+#[derive(Serialize, Debug)]
+pub struct LegacyCompletion {
+    r#type: &'static str,
+    completion: String,
+    stop_reason: Option<StopReason>,
+    model: String,
+}
+
+impl LegacyCompletion {
+    pub fn new(completion: String, stop_reason: Option<StopReason>, model: String) -> Self {
+        // legacy API only knows stop sequences and max tokens
+        let stop_reason = stop_reason.map(|r| match r {
+            StopReason::MaxTokens => StopReason::MaxTokens,
+            _ => StopReason::StopSequence,
+        });
+        LegacyCompletion {
+            r#type: "completion",
+            completion,
+            stop_reason,
+            model,
+        }
+    }
+}
+
+/// Axum handler for the legacy text completion API
+pub async fn api_complete(
+    Auth(key): Auth,
+    State(mut state): State<AppState>,
+    headers: HeaderMap,
+    Json(p): Json<LegacyRequestBody>,
+) -> Response {
+    state.format = ApiFormat::Legacy;
+    let span = debug_span(&state, &key, &headers);
+    handle_messages(key, state, headers, p.into())
+        .instrument(span)
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use rquest::Client;
+    use serde_json::{Value, json};
+
+    use crate::testing::{self, Upstream, cookie};
+
+    #[tokio::test]
+    async fn legacy_round_trip() {
+        let upstream =
+            Upstream::start(|r| async move { testing::reply(&r, &["Hello", " there"]) }).await;
+        let url = testing::serve(testing::state(testing::config(&upstream, &[cookie('a')]))).await;
+        let client = Client::new();
+        let request = |stream: bool| {
+            json!({
+                "model": "claude-2.1",
+                "prompt": "\n\nHuman: Say hello\n\nAssistant:",
+                "max_tokens_to_sample": 100,
+                "stream": stream,
+            })
+        };
+
+        let res = client
+            .post(format!("{url}/v1/complete"))
+            .json(&request(false))
+            .send()
+            .await
+            .unwrap()
+            .json::<Value>()
+            .await
+            .unwrap();
+        assert_eq!(res["type"], "completion");
+        assert_eq!(res["completion"], "Hello there");
+        assert_eq!(res["stop_reason"], "stop_sequence");
+        assert_eq!(res["model"], "claude-2.1");
+        let sent = upstream
+            .received()
+            .into_iter()
+            .find(|r| r.path.ends_with("/completion"));
+        let sent = sent.unwrap().body.to_string();
+        assert!(sent.contains("Say hello"));
+        assert!(sent.contains("\"max_tokens_to_sample\":100"));
+
+        let events = client
+            .post(format!("{url}/v1/complete"))
+            .json(&request(true))
+            .send()
+            .await
+            .unwrap()
+            .text()
+            .await
+            .unwrap();
+        let completions = events
+            .lines()
+            .filter_map(|l| l.strip_prefix("data: "))
+            .map(|d| serde_json::from_str::<Value>(d).unwrap())
+            .collect::<Vec<_>>();
+        assert!(completions.iter().all(|c| c["type"] == "completion"));
+        let text = completions
+            .iter()
+            .filter_map(|c| c["completion"].as_str())
+            .collect::<String>();
+        assert_eq!(text, "Hello there");
+        assert_eq!(completions.last().unwrap()["stop_reason"], "stop_sequence");
+    }
+}
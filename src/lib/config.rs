@@ -1,22 +1,91 @@
 use claude_tokenizer::tokenize;
 use colored::Colorize;
+use itertools::Itertools;
 use rand::{Rng, rng};
 use regex::Regex;
-use rquest::Proxy;
+use rquest::{
+    Proxy,
+    header::{HeaderMap, HeaderName, HeaderValue},
+};
 use serde::{Deserialize, Serialize};
 use std::{
+    collections::HashMap,
     fmt::{Debug, Display},
+    fs::File,
     hash::Hash,
+    io::Write,
+    mem,
+    path::Path,
+    time::Duration,
 };
-use tracing::{error, info, warn};
+use tracing::{debug, error, info, warn};
 
-use crate::{Args, error::ClewdrError, utils::config_dir};
+use crate::{
+    Args,
+    error::ClewdrError,
+    text::render_template,
+    utils::{config_dir, ct_eq, fnv1a},
+};
 
 pub const CONFIG_NAME: &str = "config.toml";
 pub const ENDPOINT: &str = "https://api.claude.ai";
+/// Minimum tokens of the pad txt file, padding is disabled below it
+pub const MIN_PAD_TOKENS: usize = 4096;
 const fn default_max_connections() -> usize {
     16
 }
+const fn default_max_concurrent_per_cookie() -> usize {
+    1
+}
+const fn default_overlap_cooldown() -> i64 {
+    5 * 60
+}
+const fn default_challenge_cooldown() -> i64 {
+    10 * 60
+}
+const fn default_max_tokens() -> u64 {
+    8192
+}
+const fn default_html_retries() -> usize {
+    1
+}
+const fn default_max_refusal_retries() -> usize {
+    2
+}
+const fn default_padtxt_stream() -> bool {
+    true
+}
+const fn default_request_log() -> bool {
+    true
+}
+const fn default_request_log_keep() -> usize {
+    20
+}
+const fn default_max_image_bytes() -> usize {
+    5 * 1024 * 1024
+}
+fn default_models() -> Vec<String> {
+    [
+        "claude-3-7-sonnet-20250219",
+        "claude-3-5-sonnet-20241022",
+        "claude-3-5-haiku-20241022",
+        "claude-3-opus-20240229",
+    ]
+    .map(String::from)
+    .to_vec()
+}
+fn default_attachment_file_name() -> String {
+    "paste.txt".to_string()
+}
+fn default_attachment_file_type() -> String {
+    "txt".to_string()
+}
+fn default_merged_system_separator() -> String {
+    "\n\n".to_string()
+}
+fn default_probe_model() -> String {
+    "claude-3-5-haiku-20241022".to_string()
+}
 
 /// A struct representing the configuration of the application
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -29,7 +98,17 @@ pub struct Config {
     // Network settings
     #[serde(default = "default_max_connections")]
     pub max_connections: usize,
+    #[serde(default = "default_max_concurrent_per_cookie")]
+    pub max_concurrent_per_cookie: usize,
     password: String,
+    /// Accept requests without credentials, only for trusted networks
+    #[serde(default)]
+    pub no_auth: bool,
+    #[serde(default)]
+    pub api_keys: Vec<ApiKey>,
+    /// Disable config changes through the API, for shared deployments
+    #[serde(default)]
+    pub read_only: bool,
     pub proxy: String,
     ip: String,
     port: u16,
@@ -37,14 +116,93 @@ pub struct Config {
     // Api settings
     #[serde(default)]
     pub pass_params: bool,
+    /// `max_tokens_to_sample` when the client omits `max_tokens`
+    /// Applied regardless of `pass_params`, as the field is always sent
+    #[serde(default = "default_max_tokens")]
+    pub default_max_tokens: u64,
     #[serde(default)]
     pub preserve_chats: bool,
+    /// Name new conversations with a hash of the prompt to spot duplicates
+    #[serde(default)]
+    pub hash_conversation_name: bool,
     #[serde(default)]
     pub skip_warning: bool,
     #[serde(default)]
     pub skip_restricted: bool,
     #[serde(default)]
     pub skip_non_pro: bool,
+    /// Models listed by `/v1/models`
+    #[serde(default = "default_models")]
+    pub models: Vec<String>,
+    /// Send one completion with `probe_model` before serving, exit if it fails
+    #[serde(default)]
+    pub startup_self_test: bool,
+    /// Cheap model used for cookie health checks instead of the requested one
+    #[serde(default = "default_probe_model")]
+    pub probe_model: String,
+    /// Seconds to bench a cookie used by another session
+    #[serde(default = "default_overlap_cooldown")]
+    pub overlap_cooldown: i64,
+    /// Successful requests before a new cookie counts as warm
+    #[serde(default)]
+    pub cold_cookie_threshold: u64,
+    /// Seconds to bench a cookie that hit a Cloudflare challenge
+    #[serde(default = "default_challenge_cooldown")]
+    pub challenge_cooldown: i64,
+    /// Retries when upstream returns an HTML error page instead of SSE
+    #[serde(default = "default_html_retries")]
+    pub html_retries: usize,
+    /// UTC hour when per-cookie daily budgets reset
+    #[serde(default)]
+    pub budget_reset_hour: u32,
+    #[serde(default)]
+    pub cookie_strategy: CookieStrategy,
+    /// Policies of cookie groups by name
+    #[serde(default)]
+    pub cookie_groups: HashMap<String, CookieGroup>,
+    #[serde(default)]
+    pub stream_mode: StreamMode,
+    /// Send held text once it reaches this many bytes without a boundary,
+    /// 0 for no limit
+    #[serde(default)]
+    pub stream_buffer_size: usize,
+    #[serde(default)]
+    pub stream_decision: StreamDecision,
+    #[serde(default)]
+    pub rendering_mode: RenderingMode,
+    /// Remove stray control characters like `\x08` from responses
+    #[serde(default)]
+    pub sanitize_control_chars: bool,
+    /// Preambles removed from the very start of responses, case-insensitive
+    #[serde(default)]
+    pub strip_response_prefixes: Vec<String>,
+    /// Cap on the response text in bytes, the response ends as if max tokens
+    /// were reached, 0 for no limit
+    #[serde(default)]
+    pub max_response_bytes: usize,
+    /// Cap on the decoded size of each image, 0 for no limit
+    #[serde(default = "default_max_image_bytes")]
+    pub max_image_bytes: usize,
+    /// Return partial content instead of waiting after this many seconds
+    #[serde(default)]
+    pub partial_on_timeout: bool,
+    #[serde(default)]
+    pub soft_completion_timeout_secs: u64,
+    /// Fail the request if upstream has not finished after this many seconds
+    #[serde(default)]
+    pub request_timeout_secs: Option<u64>,
+    /// Random delay range before each request, in milliseconds
+    #[serde(default)]
+    pub humanize_delay_min_ms: u64,
+    #[serde(default)]
+    pub humanize_delay_max_ms: u64,
+    /// Retry responses shorter than this which match `retry_refusal_pattern`
+    #[serde(default)]
+    pub retry_below_chars: usize,
+    #[serde(default)]
+    pub retry_refusal_pattern: String,
+    #[serde(default = "default_max_refusal_retries")]
+    pub max_refusal_retries: usize,
 
     // Proxy configurations
     pub rproxy: String,
@@ -56,12 +214,238 @@ pub struct Config {
     pub custom_prompt: String,
     pub padtxt_file: String,
     pub padtxt_len: usize,
+    /// Seed making the padding reproducible, random padding when unset
+    #[serde(default)]
+    pub padtxt_seed: Option<u64>,
+    /// JSON template wrapping non-streaming responses, see `render_template`
+    #[serde(default)]
+    pub response_template_file: String,
+    /// Whether to pad streaming requests
+    #[serde(default = "default_padtxt_stream")]
+    pub padtxt_stream: bool,
+    /// Number of recent turns sent inline, older ones stay in the attachment
+    #[serde(default)]
+    pub history_as_attachment_threshold: usize,
+    /// Model prefixes that get the whole prompt inline instead of a paste attachment
+    #[serde(default)]
+    pub inline_prompt_models: Vec<String>,
+    /// Skip padding and custom prompt for utility requests
+    #[serde(default)]
+    pub utility_fast_path: bool,
+    /// Name of the attachment carrying the prompt, its extension must match the type
+    #[serde(default = "default_attachment_file_name")]
+    pub attachment_file_name: String,
+    #[serde(default = "default_attachment_file_type")]
+    pub attachment_file_type: String,
+    /// Joins system messages flagged as `merged` with the following user turn
+    #[serde(default = "default_merged_system_separator")]
+    pub merged_system_separator: String,
+    /// Trim the assembled prompt and collapse long runs of blank lines
+    #[serde(default)]
+    pub trim_prompt: bool,
+    /// Also send non-streaming requests without padding on another cookie and
+    /// log how the responses diverge, the client only gets the padded one
+    #[serde(default)]
+    pub shadow_mode: bool,
+    #[serde(default)]
+    pub jailbreak_position: JailbreakPosition,
+    /// Write the prompt and response of each request into `log/requests`
+    /// Turn off to keep prompts off the disk
+    #[serde(default = "default_request_log")]
+    pub request_log: bool,
+    /// Request log directories kept, the oldest are removed, 0 keeps all
+    #[serde(default = "default_request_log_keep")]
+    pub request_log_keep: usize,
+    /// Regex replacements applied in order to the prompt after role prefixing
+    #[serde(default)]
+    pub prompt_replacements: Vec<PromptReplacement>,
 
     // Skip field
     #[serde(skip)]
     pub rquest_proxy: Option<Proxy>,
     #[serde(skip)]
     pub pad_tokens: Vec<String>,
+    #[serde(skip)]
+    pub response_template: Option<String>,
+    #[serde(skip)]
+    pub refusal_regex: Option<Regex>,
+    #[serde(skip)]
+    pub replacement_regexes: Vec<(Regex, String)>,
+    #[serde(skip)]
+    env_overrides: EnvOverrides,
+}
+
+/// Values replaced by environment variables, restored when saving
+/// so that secrets passed through the environment never reach the config file
+#[derive(Debug, Clone, Default)]
+struct EnvOverrides {
+    password: Option<String>,
+    ip: Option<String>,
+    port: Option<u16>,
+    proxy: Option<String>,
+    rproxy: Option<String>,
+    /// Cookies added from `CLEWDR_COOKIES`
+    cookies: Vec<CookieInfo>,
+}
+
+/// Regex replacement of the prompt, `$1` style groups are allowed in the replacement
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct PromptReplacement {
+    pub pattern: String,
+    #[serde(default)]
+    pub replacement: String,
+}
+
+/// Policies shared by the cookies of a group
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct CookieGroup {
+    /// In-flight conversations across the whole group, None for unlimited
+    #[serde(default)]
+    pub max_concurrent: Option<usize>,
+    /// Daily budget of cookies in the group without their own
+    #[serde(default)]
+    pub daily_budget: Option<u32>,
+    /// Overrides `overlap_cooldown` for the group
+    #[serde(default)]
+    pub overlap_cooldown: Option<i64>,
+    /// Overrides `challenge_cooldown` for the group
+    #[serde(default)]
+    pub challenge_cooldown: Option<i64>,
+}
+
+/// An extra API key besides the password
+/// A plain string is accepted as a key without a name or model limits
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(from = "RawApiKey")]
+pub struct ApiKey {
+    pub key: String,
+    /// Name of the key owner, shown in logs instead of the key
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    /// Models this key may use, empty means all models
+    #[serde(default)]
+    pub allowed_models: Vec<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RawApiKey {
+    Key(String),
+    Full {
+        key: String,
+        #[serde(default)]
+        name: Option<String>,
+        #[serde(default)]
+        allowed_models: Vec<String>,
+    },
+}
+
+impl From<RawApiKey> for ApiKey {
+    fn from(raw: RawApiKey) -> Self {
+        match raw {
+            RawApiKey::Key(key) => ApiKey {
+                key,
+                name: None,
+                allowed_models: vec![],
+            },
+            RawApiKey::Full {
+                key,
+                name,
+                allowed_models,
+            } => ApiKey {
+                key,
+                name,
+                allowed_models,
+            },
+        }
+    }
+}
+
+impl ApiKey {
+    /// Name of the key, or a short hash of it, safe to print
+    pub fn label(&self) -> String {
+        self.name
+            .clone()
+            .unwrap_or_else(|| format!("{:08x}", (fnv1a(self.key.bytes()) >> 32) as u32))
+    }
+}
+
+/// Where messages flagged as `jailbreak` are placed in the prompt
+///
+/// Ordering rules:
+/// - messages flagged as `main` always go first, right after the system prompt
+/// - jailbreak messages are then moved to the configured position
+/// - relocated messages keep their relative order
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+pub enum JailbreakPosition {
+    /// Keep jailbreak messages where they are
+    #[default]
+    InPlace,
+    /// Move jailbreak messages to the end of the prompt
+    Last,
+    /// Move jailbreak messages right before the last user message
+    BeforeLastUser,
+}
+
+/// How streamed text is batched before sent to the client
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+pub enum StreamMode {
+    /// Forward upstream bytes as is
+    #[default]
+    Chunk,
+    /// Send every text delta as soon as it arrives
+    Token,
+    /// Hold text deltas until a sentence ends
+    Sentence,
+    /// Hold text deltas until a paragraph ends
+    Paragraph,
+    /// Hold text deltas until a line ends
+    Line,
+}
+
+/// Rendering mode requested from Claude Web
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RenderingMode {
+    /// `messages` for streams, `raw` otherwise
+    #[default]
+    Auto,
+    /// `raw` for non-stream requests, streams still use `messages`
+    /// as the stream transformer only understands that shape
+    Raw,
+    Messages,
+}
+
+impl RenderingMode {
+    /// Value sent upstream for the request
+    pub fn as_str(self, stream: bool) -> &'static str {
+        match self {
+            RenderingMode::Auto | RenderingMode::Raw if stream => "messages",
+            RenderingMode::Auto | RenderingMode::Raw => "raw",
+            RenderingMode::Messages => "messages",
+        }
+    }
+}
+
+/// Which signal decides streaming when the body and `Accept` header disagree
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+pub enum StreamDecision {
+    /// The `stream` field of the body wins
+    #[default]
+    Body,
+    /// The `Accept` header wins when it names SSE or JSON
+    Accept,
+}
+
+/// How the next cookie is picked from the usable ones
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CookieStrategy {
+    /// Take cookies in turn
+    #[default]
+    RoundRobin,
+    /// Take the cookie idle for the longest time, unused cookies first
+    LeastRecentlyUsed,
+    /// Take a random cookie
+    Random,
 }
 
 /// Reason why a cookie is considered useless
@@ -73,6 +457,9 @@ pub enum Reason {
     Unverified,
     Restricted(i64),
     TooManyRequest(i64),
+    Overlap,
+    Challenge,
+    Exhausted(i64),
 }
 
 impl Display for Reason {
@@ -84,6 +471,9 @@ impl Display for Reason {
             Reason::Unverified => write!(f, "Unverified"),
             Reason::Restricted(i) => write!(f, "Restricted: {}", i),
             Reason::TooManyRequest(i) => write!(f, "Too many request: {}", i),
+            Reason::Overlap => write!(f, "Overlap"),
+            Reason::Challenge => write!(f, "Challenge"),
+            Reason::Exhausted(i) => write!(f, "Daily budget exhausted: {}", i),
         }
     }
 }
@@ -121,6 +511,28 @@ pub struct CookieStatus {
     pub reset_time: Option<i64>,
     pub discord: Option<String>,
     pub due: Option<i64>,
+    pub extra_headers: Option<HashMap<String, String>>,
+    /// Number of successful requests served
+    #[serde(default)]
+    pub requests: u64,
+    /// Timestamp when the cookie was added
+    #[serde(default)]
+    pub added_at: Option<i64>,
+    /// Maximum requests served per day, None for unlimited
+    #[serde(default)]
+    pub daily_budget: Option<u32>,
+    /// Requests served in the current budget period
+    #[serde(default)]
+    pub daily_used: u32,
+    /// Start timestamp of the current budget period
+    #[serde(default)]
+    pub budget_period: i64,
+    /// Group sharing limits and policies, e.g. cookies of one organization
+    #[serde(default)]
+    pub group: Option<String>,
+    /// Timestamp of the last successful request
+    #[serde(default)]
+    pub last_used: Option<i64>,
 }
 
 impl PartialOrd for CookieStatus {
@@ -201,8 +613,53 @@ impl CookieStatus {
             reset_time,
             discord,
             due,
+            extra_headers: None,
+            requests: 0,
+            added_at: None,
+            daily_budget: None,
+            daily_used: 0,
+            budget_period: 0,
+            group: None,
+            last_used: None,
         }
     }
+
+    /// A cookie is cold until it served enough requests, 0 disables classification
+    pub fn is_cold(&self, threshold: u64) -> bool {
+        self.requests < threshold
+    }
+
+    /// Drop extra headers with invalid names or values
+    pub fn validate_headers(&mut self) {
+        let Some(headers) = self.extra_headers.as_mut() else {
+            return;
+        };
+        headers.retain(|k, v| {
+            let valid =
+                HeaderName::from_bytes(k.as_bytes()).is_ok() && HeaderValue::from_str(v).is_ok();
+            if !valid {
+                warn!(
+                    "Invalid extra header for cookie {}: {}: {}",
+                    self.cookie, k, v
+                );
+            }
+            valid
+        });
+    }
+
+    /// Extra headers to be sent with every request using this cookie
+    pub fn headers(&self) -> HeaderMap {
+        self.extra_headers
+            .iter()
+            .flatten()
+            .filter_map(|(k, v)| {
+                Some((
+                    HeaderName::from_bytes(k.as_bytes()).ok()?,
+                    HeaderValue::from_str(v).ok()?,
+                ))
+            })
+            .collect()
+    }
 }
 
 /// A struct representing a cookie
@@ -227,6 +684,26 @@ impl CookieInfo {
         re.is_match(&self.inner)
     }
 
+    /// Masked cookie, safe to print
+    pub fn masked(&self) -> String {
+        let len = self.inner.len();
+        if len <= 18 || !self.inner.is_ascii() {
+            return "*".repeat(len);
+        }
+        format!("{}...{}", &self.inner[..12], &self.inner[len - 6..])
+    }
+
+    /// Full cookie as sent in the header, never print it
+    pub fn expose(&self) -> String {
+        format!("sessionKey={}", self.inner)
+    }
+
+    /// Stable anonymized identifier, safe to print
+    /// The same cookie always gets the same id, across restarts and edits
+    pub fn id(&self) -> String {
+        format!("{:08x}", (fnv1a(self.inner.bytes()) >> 32) as u32)
+    }
+
     pub fn clear(&mut self) {
         // Clear the cookie
         self.inner.clear();
@@ -247,21 +724,23 @@ impl From<&str> for CookieInfo {
             .to_string();
         let cookie = Self { inner: cookie };
         if !cookie.validate() {
-            warn!("Invalid cookie format: {}", original);
+            warn!("Invalid cookie format: {}", cookie.id());
         }
         cookie
     }
 }
 
 impl Display for CookieInfo {
+    /// Masked, as it may end up in logs, use `expose` for the full cookie
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "sessionKey={}", self.inner)
+        write!(f, "sessionKey={}", self.masked())
     }
 }
 
 impl Debug for CookieInfo {
+    /// Debug output only shows the id, as it may end up in logs
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "sessionKey={}", self.inner)
+        write!(f, "CookieInfo({})", self.id())
     }
 }
 
@@ -270,8 +749,7 @@ impl Serialize for CookieInfo {
     where
         S: serde::Serializer,
     {
-        let str = self.to_string();
-        serializer.serialize_str(&str)
+        serializer.serialize_str(&self.expose())
     }
 }
 
@@ -285,12 +763,54 @@ impl<'de> Deserialize<'de> for CookieInfo {
     }
 }
 
+/// Acquire an advisory lock beside the config file, released on drop
+fn lock_config(path: &Path, exclusive: bool) -> std::io::Result<File> {
+    let lock = File::options()
+        .create(true)
+        .write(true)
+        .truncate(false)
+        .open(path.with_extension("toml.lock"))?;
+    if exclusive {
+        lock.lock()?;
+    } else {
+        lock.lock_shared()?;
+    }
+    Ok(lock)
+}
+
+/// Read the config file while holding a shared lock
+fn read_config(path: &Path) -> std::io::Result<String> {
+    // do not create a lock file for a missing config
+    std::fs::metadata(path)?;
+    let _lock = lock_config(path, false)?;
+    std::fs::read_to_string(path)
+}
+
+/// Write the config file atomically while holding an exclusive lock
+/// The content is written and synced to a temp file first, then renamed over the config
+/// Blocks until the lock is acquired, so keep it off async tasks
+fn write_config(path: &Path, content: &str) -> Result<(), ClewdrError> {
+    let _lock = lock_config(path, true)?;
+    let tmp_path = path.with_extension("toml.tmp");
+    let mut tmp = File::create(&tmp_path)?;
+    tmp.write_all(content.as_bytes())?;
+    tmp.sync_all()?;
+    std::fs::rename(&tmp_path, path)?;
+    Ok(())
+}
+
+/// Masked password, only the last 4 characters are shown
+pub fn mask_password(password: &str) -> String {
+    let chars = password.chars().count();
+    if chars <= 8 {
+        return "*".repeat(chars);
+    }
+    let tail = password.chars().skip(chars - 4).collect::<String>();
+    format!("****{}", tail)
+}
+
 /// Generate a random password of given length
 fn generate_password(length: usize) -> String {
-    println!(
-        "{}",
-        "Generating random password, paste it to your proxy setting in SillyTavern".green()
-    );
     let mut rng = rng();
     (0..length)
         .map(|_| rng.random_range(33..=126) as u8 as char) // 33–126 inclusive
@@ -311,24 +831,75 @@ impl Default for Config {
             ],
             wasted_cookie: Vec::new(),
             password: String::new(),
+            no_auth: false,
+            read_only: false,
+            api_keys: Vec::new(),
             proxy: String::new(),
             ip: "127.0.0.1".to_string(),
             port: 8484,
             max_connections: default_max_connections(),
+            max_concurrent_per_cookie: default_max_concurrent_per_cookie(),
             rproxy: String::new(),
             use_real_roles: false,
             custom_prompt: String::new(),
             padtxt_file: String::new(),
             padtxt_len: 4000,
+            padtxt_seed: None,
+            response_template_file: String::new(),
+            padtxt_stream: default_padtxt_stream(),
+            utility_fast_path: false,
+            attachment_file_name: default_attachment_file_name(),
+            attachment_file_type: default_attachment_file_type(),
+            merged_system_separator: default_merged_system_separator(),
+            trim_prompt: false,
+            shadow_mode: false,
+            history_as_attachment_threshold: 0,
+            inline_prompt_models: vec![],
+            jailbreak_position: JailbreakPosition::default(),
             custom_h: None,
             custom_a: None,
             rquest_proxy: None,
             pad_tokens: Vec::new(),
+            response_template: None,
             pass_params: false,
+            default_max_tokens: default_max_tokens(),
             preserve_chats: false,
+            hash_conversation_name: false,
             skip_warning: false,
             skip_restricted: false,
             skip_non_pro: false,
+            models: default_models(),
+            startup_self_test: false,
+            probe_model: default_probe_model(),
+            overlap_cooldown: default_overlap_cooldown(),
+            cold_cookie_threshold: 0,
+            challenge_cooldown: default_challenge_cooldown(),
+            html_retries: default_html_retries(),
+            budget_reset_hour: 0,
+            cookie_strategy: CookieStrategy::default(),
+            cookie_groups: HashMap::new(),
+            stream_mode: StreamMode::default(),
+            stream_buffer_size: 0,
+            stream_decision: StreamDecision::default(),
+            rendering_mode: RenderingMode::default(),
+            sanitize_control_chars: false,
+            strip_response_prefixes: vec![],
+            max_response_bytes: 0,
+            max_image_bytes: default_max_image_bytes(),
+            partial_on_timeout: false,
+            soft_completion_timeout_secs: 0,
+            request_timeout_secs: None,
+            humanize_delay_min_ms: 0,
+            humanize_delay_max_ms: 0,
+            retry_below_chars: 0,
+            retry_refusal_pattern: String::new(),
+            max_refusal_retries: default_max_refusal_retries(),
+            refusal_regex: None,
+            request_log: default_request_log(),
+            request_log_keep: default_request_log_keep(),
+            prompt_replacements: vec![],
+            replacement_regexes: vec![],
+            env_overrides: EnvOverrides::default(),
         }
     }
 }
@@ -342,7 +913,7 @@ impl Display for Config {
             Forward Proxy: {}\n\
             Reverse Proxy: {}\n\
             Available Cookies in array: {}\n",
-            self.password.yellow(),
+            mask_password(&self.password).yellow(),
             self.proxy.to_string().blue(),
             self.rproxy.to_string().blue(),
             self.cookie_array
@@ -366,13 +937,52 @@ impl Display for Config {
 
 impl Config {
     pub fn auth(&self, key: &str) -> bool {
-        key == self.password
+        self.key_label(key).is_some()
+    }
+
+    /// Label of the key that matched, `admin` for the password
+    /// None if no key matched
+    pub fn key_label(&self, key: &str) -> Option<String> {
+        if self.is_admin(key) {
+            return Some("admin".to_string());
+        }
+        self.api_keys
+            .iter()
+            .find(|k| ct_eq(&k.key, key))
+            .map(ApiKey::label)
+    }
+
+    /// Check if the model prefers the prompt inline instead of an attachment
+    pub fn inline_prompt(&self, model: &str) -> bool {
+        self.inline_prompt_models
+            .iter()
+            .any(|m| !m.is_empty() && model.starts_with(m.as_str()))
+    }
+
+    /// Check if the key is the admin password
+    /// Everyone is admin when auth is disabled
+    pub fn is_admin(&self, key: &str) -> bool {
+        self.no_auth || ct_eq(key, &self.password)
+    }
+
+    /// Check if the key is allowed to use the model
+    /// The password can use all models
+    pub fn allows_model(&self, key: &str, model: &str) -> bool {
+        if self.is_admin(key) {
+            return true;
+        }
+        self.api_keys
+            .iter()
+            .find(|k| ct_eq(&k.key, key))
+            .is_some_and(|k| {
+                k.allowed_models.is_empty() || k.allowed_models.iter().any(|m| m == model)
+            })
     }
 
     /// Load the configuration from the file
     pub fn load() -> Result<Self, ClewdrError> {
         // try to read from pwd
-        let file_string = std::fs::read_to_string(CONFIG_NAME).or_else(|e| {
+        let file_string = read_config(Path::new(CONFIG_NAME)).or_else(|e| {
             if e.kind() == std::io::ErrorKind::NotFound {
                 // try to read from exec path
                 let exec_path = std::env::current_exe()?;
@@ -381,7 +991,7 @@ impl Config {
                     "Failed to get parent directory",
                 ))?;
                 let config_path = config_dir.join(CONFIG_NAME);
-                std::fs::read_to_string(config_path)
+                read_config(&config_path)
             } else {
                 Err(e)
             }
@@ -391,7 +1001,9 @@ impl Config {
                 // parse the config file
                 let mut config: Config = toml::de::from_str(&file_string)?;
                 config.load_from_arg_file();
+                config.load_from_env();
                 config.load_padtxt();
+                config.load_response_template();
                 config = config.validate();
                 config.save()?;
                 Ok(config)
@@ -410,6 +1022,7 @@ impl Config {
                 );
                 println!("{}", "SET YOUR COOKIE HERE".green());
                 default_config.load_from_arg_file();
+                default_config.load_from_env();
                 default_config = default_config.validate();
                 default_config.save()?;
                 Ok(default_config)
@@ -439,19 +1052,174 @@ impl Config {
         };
         // remove tokenizer special characters
         let re = Regex::new(r"[^\x00-\x7F]").unwrap();
-        let tokens = tokenize(&padtxt_string)
-            .expect("Failed to tokenize pad txt")
+        let Ok(tokens) = tokenize(&padtxt_string) else {
+            error!("Failed to tokenize pad txt file, padding disabled");
+            return;
+        };
+        let tokens = tokens
             .into_iter()
             // remove special characters
             .map(|t| re.replace_all(t.1.as_str(), "").trim().to_string())
             .filter(|t| !t.is_empty())
             .collect::<Vec<_>>();
-        if tokens.len() < 4096 {
-            panic!("Pad txt file is too short: {}", padtxt_path.display());
+        if tokens.len() < MIN_PAD_TOKENS {
+            // keep serving, requests fall back to the custom prompt
+            error!(
+                "Pad txt file is too short: {}, {} tokens, at least {} required, padding disabled",
+                padtxt_path.display(),
+                tokens.len(),
+                MIN_PAD_TOKENS
+            );
+            return;
         }
         self.pad_tokens = tokens;
     }
 
+    /// Load and validate the response template
+    fn load_response_template(&mut self) {
+        let file = &self.response_template_file;
+        if file.trim().is_empty() {
+            return;
+        }
+        let Ok(dir) = config_dir() else {
+            error!("No config found in cwd or exec dir");
+            return;
+        };
+        let path = dir.join(file);
+        let Ok(template) = std::fs::read_to_string(path.as_path()) else {
+            error!("Failed to read response template: {}", path.display());
+            return;
+        };
+        // render a sample to make sure the output is valid JSON
+        if let Err(e) = render_template(&template, "sample", "model", "end_turn") {
+            error!("Invalid response template {}: {}", path.display(), e);
+            return;
+        }
+        self.response_template = Some(template);
+    }
+
+    /// One diagnostic line per cookie, shown on startup
+    pub fn cookie_diagnostics(&self) -> Vec<String> {
+        let now = chrono::Utc::now().timestamp();
+        let usable = self.cookie_array.iter().map(|c| {
+            let status = match c.reset_time {
+                Some(t) if t > now => {
+                    format!("cooling down for {} minutes", (t - now) / 60 + 1).yellow()
+                }
+                _ => "usable".green(),
+            };
+            let status = if c.is_cold(self.cold_cookie_threshold) {
+                format!(
+                    "{}, cold: {}/{} requests",
+                    status, c.requests, self.cold_cookie_threshold
+                )
+                .cyan()
+            } else {
+                status
+            };
+            (&c.cookie, status)
+        });
+        let wasted = self
+            .wasted_cookie
+            .iter()
+            .map(|c| (&c.cookie, format!("wasted: {}", c.reason).red()));
+        usable
+            .chain(wasted)
+            .map(|(cookie, status)| {
+                let format = if cookie.validate() {
+                    "valid format".green()
+                } else {
+                    "invalid format".red()
+                };
+                format!(
+                    "{} {} [{}] [{}]",
+                    cookie.id(),
+                    cookie.masked(),
+                    format,
+                    status
+                )
+            })
+            .collect()
+    }
+
+    /// Policy of the group of a cookie, default if it has none
+    pub fn group_policy(&self, cookie: &CookieStatus) -> CookieGroup {
+        cookie
+            .group
+            .as_ref()
+            .and_then(|g| self.cookie_groups.get(g))
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// One line per cookie group with its usable and total cookies
+    pub fn group_summary(&self) -> Vec<String> {
+        let now = chrono::Utc::now().timestamp();
+        self.cookie_array
+            .iter()
+            .filter_map(|c| c.group.as_deref().map(|g| (g, c)))
+            .into_group_map()
+            .into_iter()
+            .sorted_by_key(|(g, _)| *g)
+            .map(|(group, cookies)| {
+                let usable = cookies
+                    .iter()
+                    .filter(|c| c.reset_time.is_none_or(|t| t <= now))
+                    .count();
+                format!(
+                    "Group {}: {}/{} usable",
+                    group.blue(),
+                    usable.to_string().green(),
+                    cookies.len()
+                )
+            })
+            .collect()
+    }
+
+    /// Check if a response is a short refusal which should be retried
+    /// Both the length and the pattern must match
+    pub fn is_refusal(&self, text: &str) -> bool {
+        let Some(ref re) = self.refusal_regex else {
+            return false;
+        };
+        let text = text.trim();
+        text.chars().count() < self.retry_below_chars && re.is_match(text)
+    }
+
+    /// Apply the prompt replacements in order
+    pub fn replace_prompt(&self, text: String) -> String {
+        self.replacement_regexes
+            .iter()
+            .fold(text, |text, (re, rep)| {
+                re.replace_all(&text, rep.as_str()).into_owned()
+            })
+    }
+
+    /// Soft completion timeout, if enabled
+    pub fn soft_timeout(&self) -> Option<Duration> {
+        if !self.partial_on_timeout || self.soft_completion_timeout_secs == 0 {
+            return None;
+        }
+        Some(Duration::from_secs(self.soft_completion_timeout_secs))
+    }
+
+    /// Hard request timeout, if enabled
+    pub fn request_timeout(&self) -> Option<Duration> {
+        self.request_timeout_secs
+            .filter(|&s| s > 0)
+            .map(Duration::from_secs)
+    }
+
+    /// Random delay before a request to mimic human timing
+    pub fn humanize_delay(&self) -> Duration {
+        let min = self.humanize_delay_min_ms;
+        let max = self.humanize_delay_max_ms.max(min);
+        if max == 0 {
+            return Duration::ZERO;
+        }
+        Duration::from_millis(rng().random_range(min..=max))
+    }
+
     /// API endpoint of server
     pub fn endpoint(&self) -> String {
         if self.rproxy.is_empty() {
@@ -466,15 +1234,48 @@ impl Config {
         format!("{}:{}", self.ip, self.port)
     }
 
+    /// Copy of the config with the values from environment variables taken out
+    fn persisted(&self) -> Self {
+        let mut config = self.clone();
+        let env = &self.env_overrides;
+        if let Some(password) = &env.password {
+            config.password = password.clone();
+        }
+        if let Some(ip) = &env.ip {
+            config.ip = ip.clone();
+        }
+        if let Some(port) = env.port {
+            config.port = port;
+        }
+        if let Some(proxy) = &env.proxy {
+            config.proxy = proxy.clone();
+        }
+        if let Some(rproxy) = &env.rproxy {
+            config.rproxy = rproxy.clone();
+        }
+        config
+            .cookie_array
+            .retain(|c| !env.cookies.contains(&c.cookie));
+        config
+            .wasted_cookie
+            .retain(|c| !env.cookies.contains(&c.cookie));
+        config
+    }
+
     /// Save the configuration to a file
+    /// Values from environment variables are left out
     pub fn save(&self) -> Result<(), ClewdrError> {
+        if self.read_only {
+            debug!("Read-only mode, skipping config save");
+            return Ok(());
+        }
+        let config_string = toml::ser::to_string_pretty(&self.persisted())?;
         // try find existing config file
         let existing = config_dir();
         if let Ok(existing) = existing {
             let config_path = existing.join(CONFIG_NAME);
             // overwrite the file if it exists
-            std::fs::write(config_path, toml::ser::to_string_pretty(self)?)?;
-            return Ok(());
+            return write_config(&config_path, &config_string);
         }
         // try to create a new config file in exec path or pwd
         let exec_path = std::env::current_exe()?;
@@ -487,17 +1288,64 @@ impl Config {
         }
         // Save the config to a file
         let config_path = config_dir.join(CONFIG_NAME);
-        let config_string = toml::ser::to_string_pretty(self)?;
-        std::fs::write(config_path, config_string)?;
-        Ok(())
+        write_config(&config_path, &config_string)
     }
 
     /// Validate the configuration
     fn validate(mut self) -> Self {
-        if self.password.trim().is_empty() {
+        if self.no_auth {
+            warn!(
+                "{}",
+                "Authentication is disabled, anyone who can reach the server can use it".red()
+            );
+        } else if self.password.trim().is_empty() {
             self.password = generate_password(32);
-            self.save().expect("Failed to save config");
+            if self.read_only {
+                // nothing is saved in read-only mode, this is the only chance to see it
+                println!(
+                    "{} {}",
+                    "Read-only mode, generated password for this run:".green(),
+                    self.password
+                );
+            } else {
+                println!(
+                    "{}",
+                    "Generating random password, find it in config.toml and paste it to your proxy setting in SillyTavern".green()
+                );
+                self.save().expect("Failed to save config");
+            }
+        }
+        if self.probe_model.trim().is_empty() {
+            warn!("probe_model is empty, using {}", default_probe_model());
+            self.probe_model = default_probe_model();
+        }
+        let extension = Path::new(&self.attachment_file_name)
+            .extension()
+            .and_then(|e| e.to_str());
+        if extension != Some(self.attachment_file_type.as_str()) {
+            warn!(
+                "Attachment file name {} does not match type {}, using {}",
+                self.attachment_file_name,
+                self.attachment_file_type,
+                default_attachment_file_name()
+            );
+            self.attachment_file_name = default_attachment_file_name();
+            self.attachment_file_type = default_attachment_file_type();
+        }
+        if self.budget_reset_hour > 23 {
+            warn!("budget_reset_hour must be below 24, using 0");
+            self.budget_reset_hour = 0;
+        }
+        if self.rendering_mode == RenderingMode::Raw {
+            warn!("rendering_mode Raw only applies to non-stream requests");
+        }
+        if self.max_concurrent_per_cookie == 0 {
+            warn!("max_concurrent_per_cookie must be positive, using 1");
+            self.max_concurrent_per_cookie = 1;
         }
+        self.cookie_array
+            .iter_mut()
+            .for_each(CookieStatus::validate_headers);
         self.ip = self.ip.trim().to_string();
         self.rproxy = self.rproxy.trim().to_string();
         self.proxy = self.proxy.trim().to_string();
@@ -511,6 +1359,28 @@ impl Config {
                 .ok()
         };
         self.rquest_proxy = proxy;
+        self.refusal_regex = if self.retry_refusal_pattern.trim().is_empty() {
+            None
+        } else {
+            Regex::new(self.retry_refusal_pattern.as_str())
+                .inspect_err(|e| {
+                    error!("Failed to parse refusal pattern: {}", e);
+                })
+                .ok()
+        };
+        // invalid patterns are skipped, the others still apply
+        self.replacement_regexes = self
+            .prompt_replacements
+            .iter()
+            .filter_map(|r| {
+                Regex::new(r.pattern.as_str())
+                    .inspect_err(|e| {
+                        error!("Failed to parse prompt replacement {}: {}", r.pattern, e);
+                    })
+                    .ok()
+                    .map(|re| (re, r.replacement.clone()))
+            })
+            .collect();
         self
     }
 
@@ -525,20 +1395,62 @@ impl Config {
             return;
         };
         // one line per cookie
-        let mut new_array = file_string
-            .lines()
+        self.add_cookies(file_string.lines());
+    }
+
+    /// Override config values with environment variables
+    /// Precedence is env > file > default
+    fn load_from_env(&mut self) {
+        let var = |name: &str| {
+            std::env::var(name)
+                .ok()
+                .map(|v| v.trim().to_string())
+                .filter(|v| !v.is_empty())
+        };
+        // keep the file values, they are written back instead of the overrides
+        let env = &mut self.env_overrides;
+        if let Some(password) = var("CLEWDR_PASSWORD") {
+            env.password = Some(mem::replace(&mut self.password, password));
+        }
+        if let Some(ip) = var("CLEWDR_IP") {
+            env.ip = Some(mem::replace(&mut self.ip, ip));
+        }
+        if let Some(port) = var("CLEWDR_PORT") {
+            match port.parse() {
+                Ok(port) => env.port = Some(mem::replace(&mut self.port, port)),
+                Err(_) => warn!("Invalid CLEWDR_PORT: {}", port),
+            }
+        }
+        if let Some(proxy) = var("CLEWDR_PROXY") {
+            env.proxy = Some(mem::replace(&mut self.proxy, proxy));
+        }
+        if let Some(rproxy) = var("CLEWDR_RPROXY") {
+            env.rproxy = Some(mem::replace(&mut self.rproxy, rproxy));
+        }
+        // one cookie per line or comma separated
+        if let Some(cookies) = var("CLEWDR_COOKIES") {
+            let added = self.add_cookies(cookies.split(['\n', ',']));
+            self.env_overrides.cookies = added;
+        }
+    }
+
+    /// Add new cookies, skipping invalid, duplicate and wasted ones
+    /// Returns the cookies that were added
+    fn add_cookies<'a>(&mut self, cookies: impl Iterator<Item = &'a str>) -> Vec<CookieInfo> {
+        let mut new_array = cookies
+            .filter(|line| !line.trim().is_empty())
             .filter_map(|line| {
                 let c = CookieInfo::from(line);
                 if !c.validate() {
-                    warn!("Invalid cookie format: {}", line);
+                    warn!("Invalid cookie format: {}", c.id());
                     return None;
                 }
                 if self.cookie_array.iter().any(|x| x.cookie == c) {
-                    warn!("Duplicate cookie: {}", line);
+                    warn!("Duplicate cookie: {}", c.id());
                     return None;
                 }
                 if self.wasted_cookie.iter().any(|x| x.cookie == c) {
-                    warn!("Wasted cookie: {}", line);
+                    warn!("Wasted cookie: {}", c.id());
                     return None;
                 }
                 Some(CookieStatus {
@@ -546,12 +1458,271 @@ impl Config {
                     reset_time: None,
                     discord: None,
                     due: None,
+                    extra_headers: None,
+                    requests: 0,
+                    added_at: Some(chrono::Utc::now().timestamp()),
+                    ..Default::default()
                 })
             })
             .collect::<Vec<_>>();
         // remove duplicates
         new_array.sort_unstable_by(|a, b| a.cookie.cmp(&b.cookie));
         new_array.dedup_by(|a, b| a.cookie == b.cookie);
+        let added = new_array.iter().map(|c| c.cookie.clone()).collect();
         self.cookie_array.extend(new_array);
+        added
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn concurrent_saves_leave_parseable_file() {
+        let dir = std::env::temp_dir().join(format!("clewdr-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(CONFIG_NAME);
+        let contents = (0..8)
+            .map(|i| {
+                let config = Config {
+                    port: 8000 + i,
+                    custom_prompt: "x".repeat(10_000 * (i as usize + 1)),
+                    ..Default::default()
+                };
+                toml::ser::to_string_pretty(&config).unwrap()
+            })
+            .collect::<Vec<_>>();
+        std::thread::scope(|s| {
+            for content in &contents {
+                let path = &path;
+                s.spawn(move || {
+                    for _ in 0..10 {
+                        write_config(path, content).unwrap();
+                        let read = read_config(path).unwrap();
+                        assert!(toml::from_str::<Config>(&read).is_ok());
+                    }
+                });
+            }
+        });
+        let saved = toml::from_str::<Config>(&read_config(&path).unwrap()).unwrap();
+        let i = saved.port - 8000;
+        assert_eq!(saved.custom_prompt.len(), 10_000 * (i as usize + 1));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn env_overrides_are_not_persisted() {
+        let mut config = Config {
+            password: "env-password".to_string(),
+            env_overrides: EnvOverrides {
+                password: Some("file-password".to_string()),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let cookie = format!("sk-ant-sid01-{}-{}AA", "e".repeat(86), "n".repeat(6));
+        let added = config.add_cookies([cookie.as_str()].into_iter());
+        assert_eq!(added.len(), 1);
+        config.env_overrides.cookies = added;
+        let persisted = config.persisted();
+        assert_eq!(persisted.password, "file-password");
+        assert_eq!(persisted.cookie_array.len(), config.cookie_array.len() - 1);
+        let saved = toml::ser::to_string_pretty(&persisted).unwrap();
+        assert!(!saved.contains("env-password"));
+        assert!(!saved.contains(&cookie));
+    }
+
+    #[test]
+    fn short_refusal_retried_short_answer_kept() {
+        let config = Config {
+            no_auth: true,
+            read_only: true,
+            retry_below_chars: 100,
+            retry_refusal_pattern: "(?i)^I (can't|cannot|won't)".to_string(),
+            ..Default::default()
+        }
+        .validate();
+        assert!(config.is_refusal("I can't help with that."));
+        assert!(config.is_refusal("  I cannot continue this story.\n"));
+        assert!(!config.is_refusal("Sure! The capital of France is Paris."));
+        assert!(!config.is_refusal("I can't wait to tell you. ".repeat(10).as_str()));
+        let disabled = Config {
+            retry_below_chars: 100,
+            ..Default::default()
+        };
+        assert!(!disabled.is_refusal("I can't help with that."));
+    }
+
+    #[test]
+    fn no_auth_skips_password_generation() {
+        let open = Config {
+            no_auth: true,
+            read_only: true,
+            ..Default::default()
+        }
+        .validate();
+        assert!(open.password.is_empty());
+        assert!(open.auth(""));
+        assert!(open.is_admin("anything"));
+        let guarded = Config {
+            read_only: true,
+            ..Default::default()
+        }
+        .validate();
+        assert_eq!(guarded.password.len(), 32);
+        assert!(!guarded.auth(""));
+    }
+
+    #[test]
+    fn invalid_extra_headers_dropped_on_load() {
+        let cookie = format!("sk-ant-sid01-{}-{}AA", "h".repeat(86), "d".repeat(6));
+        let headers = [
+            ("X-Device-Id", "device-a"),
+            ("Bad Name", "v"),
+            ("X-Bad-Value", "a\nb"),
+        ];
+        let config = Config {
+            no_auth: true,
+            read_only: true,
+            cookie_array: vec![CookieStatus {
+                extra_headers: Some(
+                    headers
+                        .iter()
+                        .map(|(k, v)| (k.to_string(), v.to_string()))
+                        .collect(),
+                ),
+                ..CookieStatus::new(&cookie, None, None, None)
+            }],
+            ..Default::default()
+        };
+        let saved = toml::ser::to_string_pretty(&config).unwrap();
+        let loaded = toml::from_str::<Config>(&saved).unwrap().validate();
+        let headers = loaded.cookie_array[0].headers();
+        assert_eq!(headers.len(), 1);
+        assert_eq!(headers["x-device-id"], "device-a");
+        let extra = loaded.cookie_array[0].extra_headers.as_ref().unwrap();
+        assert_eq!(extra.len(), 1);
+    }
+
+    #[test]
+    fn mismatched_attachment_extension_uses_default() {
+        let config = Config {
+            no_auth: true,
+            read_only: true,
+            attachment_file_name: "context.md".to_string(),
+            attachment_file_type: "txt".to_string(),
+            ..Default::default()
+        }
+        .validate();
+        assert_eq!(config.attachment_file_name, default_attachment_file_name());
+        assert_eq!(config.attachment_file_type, default_attachment_file_type());
+    }
+
+    #[test]
+    fn humanize_delay_within_bounds() {
+        let delay = |min, max| {
+            Config {
+                humanize_delay_min_ms: min,
+                humanize_delay_max_ms: max,
+                ..Default::default()
+            }
+            .humanize_delay()
+        };
+        assert_eq!(delay(0, 0), Duration::ZERO);
+        for _ in 0..100 {
+            let d = delay(200, 800);
+            assert!((Duration::from_millis(200)..=Duration::from_millis(800)).contains(&d));
+        }
+        // a max below the min is raised to it
+        assert_eq!(delay(300, 100), Duration::from_millis(300));
+        assert!(delay(0, 1) <= Duration::from_millis(1));
+    }
+
+    #[test]
+    fn diagnostics_flag_invalid_cookies() {
+        colored::control::set_override(false);
+        let valid = format!("sk-ant-sid01-{}-{}AA", "v".repeat(86), "d".repeat(6));
+        let cooling = format!("sk-ant-sid01-{}-{}AA", "c".repeat(86), "d".repeat(6));
+        let now = chrono::Utc::now().timestamp();
+        let config = Config {
+            cookie_array: vec![
+                CookieStatus::new(&valid, None, None, None),
+                CookieStatus::new(&cooling, Some(now + 10 * 60 + 30), None, None),
+                CookieStatus::new("not-a-cookie", None, None, None),
+            ],
+            wasted_cookie: vec![UselessCookie::new(
+                CookieInfo::from(valid.replace('v', "w").as_str()),
+                Reason::Banned,
+            )],
+            ..Default::default()
+        };
+        let lines = config.cookie_diagnostics();
+        assert_eq!(lines.len(), 4);
+        let ends = |i: usize, suffix: &str| {
+            assert!(lines[i].ends_with(suffix), "{}", lines[i]);
+        };
+        ends(0, "[valid format] [usable]");
+        ends(1, "[valid format] [cooling down for 11 minutes]");
+        ends(2, "[invalid format] [usable]");
+        ends(3, "[valid format] [wasted: Banned]");
+        let id = CookieInfo::from(valid.as_str()).id();
+        assert!(lines[0].starts_with(&id));
+        assert!(!lines.iter().any(|l| l.contains(&valid)));
+    }
+
+    #[test]
+    fn cookie_id_stable_and_distinct() {
+        let cookie = |c: &str| format!("sk-ant-sid01-{}-{}AA", c.repeat(86), c.repeat(6));
+        let a = CookieInfo::from(cookie("a").as_str());
+        // the id does not depend on how the cookie was pasted
+        let pasted = CookieInfo::from(format!("sessionKey={}", cookie("a")).as_str());
+        assert_eq!(a.id(), pasted.id());
+        assert_eq!(a.id(), a.clone().id());
+        // pinned, ids in logs and metrics must survive restarts and upgrades
+        assert_eq!(a.id(), "54124fe8");
+        assert_eq!(a.id().len(), 8);
+        assert!(a.id().chars().all(|c| c.is_ascii_hexdigit()));
+        let ids = ["a", "b", "c", "d", "e"]
+            .map(|c| CookieInfo::from(cookie(c).as_str()).id())
+            .into_iter()
+            .collect::<std::collections::HashSet<_>>();
+        assert_eq!(ids.len(), 5);
+    }
+
+    #[test]
+    fn auth_rejects_wrong_suffix() {
+        let config = Config {
+            password: "password-1234".to_string(),
+            api_keys: vec![ApiKey::from(RawApiKey::Key("key-abcd".to_string()))],
+            ..Default::default()
+        };
+        assert!(config.auth("password-1234"));
+        assert!(config.auth("key-abcd"));
+        assert!(!config.auth("password-1235"));
+        assert!(!config.auth("password-123"));
+        assert!(!config.auth("key-abce"));
+        assert!(!config.is_admin("key-abcd"));
+    }
+
+    #[test]
+    fn key_limited_to_its_models() {
+        let config = Config {
+            password: "password-1234".to_string(),
+            api_keys: vec![
+                ApiKey::from(RawApiKey::Key("key-all".to_string())),
+                ApiKey {
+                    key: "key-one".to_string(),
+                    name: None,
+                    allowed_models: vec!["claude-a".to_string()],
+                },
+            ],
+            ..Default::default()
+        };
+        assert!(config.allows_model("key-one", "claude-a"));
+        assert!(!config.allows_model("key-one", "claude-b"));
+        assert!(config.allows_model("key-all", "claude-b"));
+        assert!(config.allows_model("password-1234", "claude-b"));
+        assert!(!config.allows_model("unknown", "claude-a"));
     }
 }
@@ -1,24 +1,73 @@
+use itertools::Itertools;
+use rand::Rng;
+use serde::Serialize;
 use std::collections::{HashMap, HashSet, VecDeque};
 use tokio::{
-    select,
-    sync::{mpsc::Receiver, oneshot},
+    select, spawn,
+    sync::{mpsc::Receiver, oneshot, watch},
+    task::spawn_blocking,
     time::{Instant, Interval},
 };
 use tracing::{error, info, warn};
 
 use crate::{
-    config::{Config, CookieStatus, Reason, UselessCookie},
+    config::{Config, CookieInfo, CookieStatus, CookieStrategy, Reason, UselessCookie},
     error::ClewdrError,
+    metrics::METRICS,
 };
 
+/// Counts of the cookie pool, published after every change
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct PoolStatus {
+    /// Cookies in rotation or in use
+    pub usable: usize,
+    /// Cookies cooling down until their reset time
+    pub exhausted: usize,
+    /// Cookies dropped for good
+    pub banned: usize,
+}
+
+/// Cookie as listed by the admin API, the cookie itself is masked
+#[derive(Debug, Clone, Serialize)]
+pub struct CookieEntry {
+    /// Position in the list, used to remove the cookie
+    pub index: usize,
+    pub id: String,
+    pub cookie: String,
+    pub reset_time: Option<i64>,
+    /// Why the cookie was dropped, None if it is still in the pool
+    pub reason: Option<Reason>,
+    #[serde(skip)]
+    info: CookieInfo,
+}
+
+/// Admin operations on the cookie pool, answered through the oneshot
+pub enum CookieOp {
+    /// List all cookies, dropped ones last
+    List(oneshot::Sender<Vec<CookieEntry>>),
+    /// Remove the cookie at the index of the list
+    Remove(usize, oneshot::Sender<Option<CookieEntry>>),
+}
+
 pub struct CookieManager {
     valid: VecDeque<CookieStatus>,
-    dispatched: HashMap<CookieStatus, Instant>,
+    /// dispatch time of every in-flight conversation per cookie
+    dispatched: HashMap<CookieStatus, Vec<Instant>>,
     exhausted: HashSet<CookieStatus>,
     invalid: HashSet<UselessCookie>,
+    /// successful requests per cookie
+    requests: HashMap<CookieInfo, u64>,
+    /// budget period start and requests served in it per cookie
+    daily: HashMap<CookieInfo, (i64, u32)>,
+    /// timestamp of the last successful request per cookie
+    last_used: HashMap<CookieInfo, i64>,
     req_rx: Receiver<oneshot::Sender<Result<CookieStatus, ClewdrError>>>,
     ret_rx: Receiver<(CookieStatus, Option<Reason>)>,
     submit_rx: Receiver<CookieStatus>,
+    admin_rx: Receiver<CookieOp>,
+    status_tx: watch::Sender<PoolStatus>,
+    /// latest config to write, saved off the event loop
+    save_tx: watch::Sender<Config>,
     config: Config,
     interval: Interval,
 }
@@ -27,14 +76,14 @@ impl CookieStatus {
     /// check if the cookie is expired
     /// if expired, set the reset time to None
     pub fn reset(self) -> Self {
-        if let Some(t) = self.reset_time {
-            if t < chrono::Utc::now().timestamp() {
-                info!("Cookie reset time expired");
-                return Self {
-                    reset_time: None,
-                    ..self
-                };
-            }
+        if let Some(t) = self.reset_time
+            && t < chrono::Utc::now().timestamp()
+        {
+            info!("Cookie reset time expired");
+            return Self {
+                reset_time: None,
+                ..self
+            };
         }
         self
     }
@@ -46,6 +95,8 @@ impl CookieManager {
         req_rx: Receiver<oneshot::Sender<Result<CookieStatus, ClewdrError>>>,
         ret_rx: Receiver<(CookieStatus, Option<Reason>)>,
         submit_rx: Receiver<CookieStatus>,
+        admin_rx: Receiver<CookieOp>,
+        status_tx: watch::Sender<PoolStatus>,
     ) -> Self {
         config.cookie_array = config.cookie_array.into_iter().map(|c| c.reset()).collect();
         let valid = VecDeque::from_iter(config.cookie_array.iter().filter_map(|c| {
@@ -64,16 +115,38 @@ impl CookieManager {
         }));
         let invalid = HashSet::from_iter(config.wasted_cookie.iter().cloned());
         let dispatched = HashMap::new();
+        let requests = config
+            .cookie_array
+            .iter()
+            .map(|c| (c.cookie.clone(), c.requests))
+            .collect();
+        let daily = config
+            .cookie_array
+            .iter()
+            .map(|c| (c.cookie.clone(), (c.budget_period, c.daily_used)))
+            .collect();
+        let last_used = config
+            .cookie_array
+            .iter()
+            .filter_map(|c| Some((c.cookie.clone(), c.last_used?)))
+            .collect();
         // wait 5 mins to collect unreturned cookies
         let interval = tokio::time::interval(std::time::Duration::from_secs(5 * 60));
+        let (save_tx, _) = watch::channel(config.clone());
         Self {
             valid,
             exhausted: exhaust,
             invalid,
+            requests,
+            daily,
+            last_used,
             req_rx,
             config,
             ret_rx,
             submit_rx,
+            admin_rx,
+            status_tx,
+            save_tx,
             dispatched,
             interval,
         }
@@ -87,6 +160,17 @@ impl CookieManager {
             self.exhausted.len(),
             self.invalid.len()
         );
+        let usable = self
+            .valid
+            .iter()
+            .chain(self.dispatched.keys())
+            .unique()
+            .count();
+        self.status_tx.send_replace(PoolStatus {
+            usable,
+            exhausted: self.exhausted.len(),
+            banned: self.invalid.len(),
+        });
     }
 
     fn save(&mut self) {
@@ -95,12 +179,38 @@ impl CookieManager {
             .iter()
             .chain(self.exhausted.iter())
             .chain(self.dispatched.keys())
-            .cloned()
+            .unique()
+            .map(|c| {
+                let (budget_period, daily_used) =
+                    self.daily.get(&c.cookie).copied().unwrap_or_default();
+                CookieStatus {
+                    requests: self.requests.get(&c.cookie).copied().unwrap_or_default(),
+                    budget_period,
+                    daily_used,
+                    last_used: self.last_used.get(&c.cookie).copied(),
+                    ..c.clone()
+                }
+            })
             .collect::<Vec<_>>();
         self.config.wasted_cookie = self.invalid.iter().cloned().collect();
-        self.config.save().unwrap_or_else(|e| {
-            error!("Failed to save config: {}", e);
-        });
+        self.save_tx.send_replace(self.config.clone());
+    }
+
+    /// Write the configs sent by `save` one at a time on the blocking pool
+    /// Only the latest one is written when several are sent during a write
+    async fn write_configs(mut save_rx: watch::Receiver<Config>) {
+        while save_rx.changed().await.is_ok() {
+            let config = save_rx.borrow_and_update().clone();
+            let res = spawn_blocking(move || config.save()).await;
+            if let Ok(Err(e)) = res {
+                error!("Failed to save config: {}", e);
+            }
+        }
+    }
+
+    /// Soonest reset time of cooling down cookies
+    fn soonest_reset(&self) -> Option<i64> {
+        self.exhausted.iter().filter_map(|c| c.reset_time).min()
     }
 
     /// Try to dispatch a cookie from the valid set
@@ -117,25 +227,150 @@ impl CookieManager {
         });
         self.valid.extend(reset_cookies);
         self.save();
-        // randomly select a cookie from valid cookies and remove it from the set
-        let cookie = self
-            .valid
-            .pop_front()
-            .ok_or(ClewdrError::NoCookieAvailable)?;
+        // select a cookie from valid cookies and remove it from the set
+        let mut skipped = Vec::new();
+        let picked = loop {
+            let Some(cookie) = self.pick() else {
+                break None;
+            };
+            if self.group_full(&cookie) {
+                skipped.push(cookie);
+                continue;
+            }
+            if self.spend_budget(&cookie) {
+                break Some(cookie);
+            }
+            self.bench_exhausted(cookie);
+        };
+        // cookies of full groups keep their place in rotation
+        for c in skipped.into_iter().rev() {
+            self.valid.push_front(c);
+        }
+        let Some(cookie) = picked else {
+            return Err(ClewdrError::NoCookieAvailable(self.soonest_reset()));
+        };
+        METRICS.cookie_requests.inc(&cookie.cookie.id());
         let instant = Instant::now();
-        self.dispatched.insert(cookie.clone(), instant);
+        let slots = self.dispatched.entry(cookie.clone()).or_default();
+        slots.push(instant);
+        // keep the cookie in rotation until it reaches the concurrency limit
+        if slots.len() < self.config.max_concurrent_per_cookie {
+            self.valid.push_back(cookie.clone());
+        }
         Ok(cookie)
     }
 
+    /// Release one in-flight conversation of a cookie
+    /// Put the cookie back into rotation if it was at its limit
+    fn release(&mut self, cookie: &CookieStatus) {
+        let Some(slots) = self.dispatched.get_mut(cookie) else {
+            return;
+        };
+        slots.remove(0);
+        if slots.is_empty() {
+            self.dispatched.remove(cookie);
+        }
+        if !self.valid.contains(cookie) && !self.exhausted.contains(cookie) {
+            self.valid.push_back(cookie.clone());
+        }
+    }
+
+    /// Take the next cookie out of the valid ones according to the strategy
+    fn pick(&mut self) -> Option<CookieStatus> {
+        let index = match self.config.cookie_strategy {
+            CookieStrategy::RoundRobin => 0,
+            CookieStrategy::LeastRecentlyUsed => self
+                .valid
+                .iter()
+                .position_min_by_key(|c| self.last_used.get(&c.cookie))?,
+            CookieStrategy::Random => rand::rng().random_range(0..self.valid.len().max(1)),
+        };
+        self.valid.remove(index)
+    }
+
+    /// Check if the group of a cookie reached its concurrency limit
+    fn group_full(&self, cookie: &CookieStatus) -> bool {
+        let Some(limit) = self.config.group_policy(cookie).max_concurrent else {
+            return false;
+        };
+        let in_flight: usize = self
+            .dispatched
+            .iter()
+            .filter(|(c, _)| c.group == cookie.group)
+            .map(|(_, slots)| slots.len())
+            .sum();
+        in_flight >= limit
+    }
+
+    /// Count a request against the daily budget of a cookie
+    /// Returns false if the budget of the current period is used up
+    fn spend_budget(&mut self, cookie: &CookieStatus) -> bool {
+        let budget = cookie
+            .daily_budget
+            .or(self.config.group_policy(cookie).daily_budget);
+        let Some(budget) = budget else {
+            return true;
+        };
+        let period = budget_period(
+            chrono::Utc::now().timestamp(),
+            self.config.budget_reset_hour,
+        );
+        let (start, used) = self.daily.entry(cookie.cookie.clone()).or_default();
+        if *start != period {
+            *start = period;
+            *used = 0;
+        }
+        if *used >= budget {
+            return false;
+        }
+        *used += 1;
+        true
+    }
+
+    /// Bench a cookie that used up its daily budget until the next period
+    fn bench_exhausted(&mut self, mut cookie: CookieStatus) {
+        let period = budget_period(
+            chrono::Utc::now().timestamp(),
+            self.config.budget_reset_hour,
+        );
+        let reset = period + 24 * 60 * 60;
+        info!(
+            "{}, cookie: {}",
+            Reason::Exhausted(reset),
+            cookie.cookie.id()
+        );
+        METRICS.record_retirement(&Reason::Exhausted(reset));
+        self.valid.retain(|c| *c != cookie);
+        cookie.reset_time = Some(reset);
+        self.exhausted.insert(cookie);
+        self.save();
+    }
+
+    /// Count a successful request, a cold cookie warms up after enough of them
+    fn count_request(&mut self, cookie: &CookieStatus) {
+        self.last_used
+            .insert(cookie.cookie.clone(), chrono::Utc::now().timestamp());
+        let count = self.requests.entry(cookie.cookie.clone()).or_default();
+        *count += 1;
+        if *count == self.config.cold_cookie_threshold {
+            info!("Cookie warmed up: {}", cookie.cookie.id());
+        }
+    }
+
     /// Collect the cookie and update the state
     fn collect(&mut self, mut cookie: CookieStatus, reason: Option<Reason>) {
-        let Some(_) = self.dispatched.remove(&cookie) else {
+        if !self.dispatched.contains_key(&cookie) {
             return;
-        };
+        }
         let Some(reason) = reason else {
-            self.valid.push_back(cookie);
+            self.count_request(&cookie);
+            self.release(&cookie);
             return;
         };
+        METRICS.record_retirement(&reason);
+        // drop the cookie from rotation, other in-flight uses are ignored
+        self.dispatched.remove(&cookie);
+        self.valid.retain(|c| *c != cookie);
         match reason {
             Reason::TooManyRequest(i) => {
                 cookie.reset_time = Some(i);
@@ -145,11 +380,37 @@ impl CookieManager {
                 cookie.reset_time = Some(i);
                 self.exhausted.insert(cookie);
             }
+            Reason::Overlap => {
+                warn!("Cookie used by another session: {}", cookie.cookie.id());
+                let cooldown = self
+                    .config
+                    .group_policy(&cookie)
+                    .overlap_cooldown
+                    .unwrap_or(self.config.overlap_cooldown);
+                let i = chrono::Utc::now().timestamp() + cooldown;
+                cookie.reset_time = Some(i);
+                self.exhausted.insert(cookie);
+            }
+            Reason::Challenge => {
+                warn!("Cloudflare challenge for cookie: {}", cookie.cookie.id());
+                let cooldown = self
+                    .config
+                    .group_policy(&cookie)
+                    .challenge_cooldown
+                    .unwrap_or(self.config.challenge_cooldown);
+                let i = chrono::Utc::now().timestamp() + cooldown;
+                cookie.reset_time = Some(i);
+                self.exhausted.insert(cookie);
+            }
+            Reason::Exhausted(i) => {
+                cookie.reset_time = Some(i);
+                self.exhausted.insert(cookie);
+            }
             Reason::NonPro => {
                 warn!(
                     "疑似爆米了, id: {}, cookie: {}",
                     cookie.discord.unwrap_or_default(),
-                    cookie.cookie
+                    cookie.cookie.id()
                 );
                 self.invalid
                     .insert(UselessCookie::new(cookie.cookie, reason));
@@ -161,7 +422,7 @@ impl CookieManager {
         self.save();
     }
 
-    fn accept(&mut self, cookie: CookieStatus) {
+    fn accept(&mut self, mut cookie: CookieStatus) {
         if self.config.cookie_array.contains(&cookie)
             || self
                 .config
@@ -172,15 +433,77 @@ impl CookieManager {
             warn!("Cookie already exists");
             return;
         }
+        cookie.requests = 0;
+        cookie.added_at = Some(chrono::Utc::now().timestamp());
         self.config.cookie_array.push(cookie.clone());
+        // saving rebuilds the list from the pool, so the cookie must be in it first
+        self.valid.push_back(cookie);
         self.save();
-        self.valid.push_back(cookie.clone());
+    }
+
+    /// All cookies sorted by cookie, dropped ones last
+    /// Indices stay the same as long as no cookie is added or removed
+    fn entries(&self) -> Vec<CookieEntry> {
+        let pooled = self
+            .valid
+            .iter()
+            .chain(self.exhausted.iter())
+            .chain(self.dispatched.keys())
+            .unique()
+            .sorted_by(|a, b| a.cookie.cmp(&b.cookie))
+            .map(|c| (&c.cookie, c.reset_time, None));
+        let dropped = self
+            .invalid
+            .iter()
+            .sorted_by(|a, b| a.cookie.cmp(&b.cookie))
+            .map(|c| (&c.cookie, None, Some(c.reason.clone())));
+        pooled
+            .chain(dropped)
+            .enumerate()
+            .map(|(index, (info, reset_time, reason))| CookieEntry {
+                index,
+                id: info.id(),
+                cookie: info.masked(),
+                reset_time,
+                reason,
+                info: info.clone(),
+            })
+            .collect()
+    }
+
+    /// Remove a cookie and everything known about it
+    /// An in-flight use is ignored when the cookie comes back
+    fn remove(&mut self, index: usize) -> Option<CookieEntry> {
+        let entry = self.entries().into_iter().nth(index)?;
+        let info = &entry.info;
+        self.valid.retain(|c| c.cookie != *info);
+        self.exhausted.retain(|c| c.cookie != *info);
+        self.dispatched.retain(|c, _| c.cookie != *info);
+        self.invalid.retain(|c| c.cookie != *info);
+        self.requests.remove(info);
+        self.daily.remove(info);
+        self.last_used.remove(info);
+        info!("Cookie removed: {}", info.id());
+        self.save();
+        Some(entry)
+    }
+
+    /// Answer an admin operation
+    fn admin(&mut self, op: CookieOp) {
+        let sent = match op {
+            CookieOp::List(tx) => tx.send(self.entries()).is_ok(),
+            CookieOp::Remove(index, tx) => tx.send(self.remove(index)).is_ok(),
+        };
+        if !sent {
+            error!("Failed to answer cookie admin operation");
+        }
     }
 
     /// Run the cookie manager
     /// This function will run in a loop and handle the requests and returns
     /// from the channels
     pub async fn run(mut self) {
+        spawn(Self::write_configs(self.save_tx.subscribe()));
         loop {
             self.log();
             select! {
@@ -189,19 +512,23 @@ impl CookieManager {
                 Some(cookie) = self.submit_rx.recv() => {
                     self.accept(cookie);
                 }
+                Some(op) = self.admin_rx.recv() => self.admin(op),
                 _ = self.interval.tick() => {
                     // collect cookies that are not returned for 5 mins
                     let now = Instant::now();
                     let expired: Vec<CookieStatus> = self.dispatched
                         .iter()
-                        .filter(|(_, time)| now.duration_since(**time).as_secs() > 5 * 60)
-                        .map(|(cookie, _)| cookie.clone())
+                        .flat_map(|(cookie, times)| {
+                            times
+                                .iter()
+                                .filter(|time| now.duration_since(**time).as_secs() > 5 * 60)
+                                .map(move |_| cookie.clone())
+                        })
                         .collect();
 
                     for cookie in expired {
                         warn!("Timing out dispatched cookie: {:?}", cookie);
-                        self.dispatched.remove(&cookie);
-                        self.valid.push_back(cookie);
+                        self.release(&cookie);
                     }
                 }
                 Some(sender) = self.req_rx.recv() => {
@@ -209,7 +536,7 @@ impl CookieManager {
                     if let Err(e) = sender.send(cookie) {
                         error!("Failed to send cookie");
                         if let Ok(c) = e {
-                            self.valid.push_back(c);
+                            self.release(&c);
                         }
                     }
                 }
@@ -217,3 +544,174 @@ impl CookieManager {
         }
     }
 }
+
+/// Start of the daily budget period containing `now`, periods begin at `hour` UTC
+fn budget_period(now: i64, hour: u32) -> i64 {
+    const DAY: i64 = 24 * 60 * 60;
+    let offset = hour as i64 * 60 * 60;
+    (now - offset).div_euclid(DAY) * DAY + offset
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::sync::mpsc;
+
+    use super::*;
+    use crate::config::CookieGroup;
+
+    fn manager(cookies: &[&str], max_concurrent: usize) -> CookieManager {
+        let mut config = Config::default();
+        config.cookie_array = cookies
+            .iter()
+            .map(|c| CookieStatus::new(c, None, None, None))
+            .collect();
+        config.read_only = true;
+        config.max_concurrent_per_cookie = max_concurrent;
+        let (_, req_rx) = mpsc::channel(1);
+        let (_, ret_rx) = mpsc::channel(1);
+        let (_, submit_rx) = mpsc::channel(1);
+        let (_, admin_rx) = mpsc::channel(1);
+        let (status_tx, _) = watch::channel(PoolStatus::default());
+        CookieManager::new(config, req_rx, ret_rx, submit_rx, admin_rx, status_tx)
+    }
+
+    #[tokio::test]
+    async fn cookie_at_limit_is_skipped() {
+        let mut manager = manager(&["cookie-a", "cookie-b"], 1);
+        let first = manager.dispatch().unwrap();
+        let second = manager.dispatch().unwrap();
+        assert_ne!(first.cookie, second.cookie);
+        assert!(matches!(
+            manager.dispatch(),
+            Err(ClewdrError::NoCookieAvailable(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn returned_cookie_is_dispatched_again() {
+        let mut manager = manager(&["cookie-a"], 1);
+        let cookie = manager.dispatch().unwrap();
+        assert!(manager.dispatch().is_err());
+        manager.collect(cookie.clone(), None);
+        assert_eq!(manager.dispatch().unwrap().cookie, cookie.cookie);
+    }
+
+    #[tokio::test]
+    async fn cookie_shared_up_to_limit() {
+        let mut manager = manager(&["cookie-a", "cookie-b"], 2);
+        let first = manager.dispatch().unwrap();
+        let second = manager.dispatch().unwrap();
+        let third = manager.dispatch().unwrap();
+        assert_ne!(first.cookie, second.cookie);
+        assert_eq!(first.cookie, third.cookie);
+    }
+
+    #[tokio::test]
+    async fn group_limit_independent_of_other_groups() {
+        let mut manager = manager(&["cookie-a1", "cookie-a2", "cookie-b1", "cookie-b2"], 10);
+        for c in manager.valid.iter_mut() {
+            let group = if c.cookie.expose().contains("cookie-a") {
+                "a"
+            } else {
+                "b"
+            };
+            c.group = Some(group.to_string());
+        }
+        manager.config.cookie_groups = HashMap::from([
+            (
+                "a".to_string(),
+                CookieGroup {
+                    max_concurrent: Some(1),
+                    ..Default::default()
+                },
+            ),
+            (
+                "b".to_string(),
+                CookieGroup {
+                    max_concurrent: Some(3),
+                    ..Default::default()
+                },
+            ),
+        ]);
+        let group = |c: &CookieStatus| c.group.clone().unwrap();
+        let dispatched = (0..4)
+            .map(|_| manager.dispatch().unwrap())
+            .collect::<Vec<_>>();
+        let in_a = dispatched.iter().filter(|c| group(c) == "a").count();
+        assert_eq!((in_a, dispatched.len() - in_a), (1, 3));
+        // both groups are at their limit
+        assert!(manager.dispatch().is_err());
+        // a slot freed in one group does not open the other
+        let b = dispatched.iter().find(|c| group(c) == "b").unwrap();
+        manager.collect(b.clone(), None);
+        assert_eq!(group(&manager.dispatch().unwrap()), "b");
+        assert!(manager.dispatch().is_err());
+        let a = dispatched.iter().find(|c| group(c) == "a").unwrap();
+        manager.collect(a.clone(), None);
+        assert_eq!(group(&manager.dispatch().unwrap()), "a");
+    }
+
+    #[tokio::test]
+    async fn new_cookie_warms_after_threshold() {
+        let mut manager = manager(&[], 1);
+        manager.config.cold_cookie_threshold = 3;
+        manager.accept(CookieStatus::new("cookie-new", None, None, None));
+        let cold = |m: &CookieManager| m.config.cookie_array[0].is_cold(3);
+        assert!(cold(&manager));
+        for _ in 0..3 {
+            assert!(cold(&manager));
+            let cookie = manager.dispatch().unwrap();
+            manager.collect(cookie, None);
+            manager.save();
+        }
+        assert_eq!(manager.config.cookie_array[0].requests, 3);
+        assert!(!cold(&manager));
+    }
+
+    #[tokio::test]
+    async fn failed_requests_do_not_warm() {
+        let mut manager = manager(&["cookie-a"], 1);
+        let cookie = manager.dispatch().unwrap();
+        manager.collect(cookie, Some(Reason::Overlap));
+        manager.save();
+        assert_eq!(manager.config.cookie_array[0].requests, 0);
+    }
+
+    #[test]
+    fn budget_period_starts_at_reset_hour() {
+        const DAY: i64 = 24 * 60 * 60;
+        // 2024-01-02 01:00 UTC
+        let now = 1704157200;
+        assert_eq!(budget_period(now, 0), 1704153600);
+        // before 5:00 the period started the day before
+        assert_eq!(budget_period(now, 5), 1704153600 - DAY + 5 * 60 * 60);
+        assert_eq!(
+            budget_period(1704153600 + 5 * 60 * 60, 5),
+            1704153600 + 5 * 60 * 60
+        );
+    }
+
+    #[tokio::test]
+    async fn cookie_at_budget_is_skipped() {
+        let mut manager = manager(&["cookie-a", "cookie-b"], 10);
+        manager.valid[0].daily_budget = Some(1);
+        let budgeted = manager.valid[0].cookie.clone();
+        assert_eq!(manager.dispatch().unwrap().cookie, budgeted);
+        for _ in 0..3 {
+            assert_ne!(manager.dispatch().unwrap().cookie, budgeted);
+        }
+        assert!(manager.exhausted.iter().any(|c| c.cookie == budgeted));
+    }
+
+    #[tokio::test]
+    async fn budget_resets_in_new_period() {
+        let mut manager = manager(&["cookie-a"], 10);
+        let mut cookie = manager.valid[0].clone();
+        cookie.daily_budget = Some(1);
+        assert!(manager.spend_budget(&cookie));
+        assert!(!manager.spend_budget(&cookie));
+        // pretend the last request was in the previous period
+        manager.daily.get_mut(&cookie.cookie).unwrap().0 -= 24 * 60 * 60;
+        assert!(manager.spend_budget(&cookie));
+    }
+}
@@ -1,5 +1,8 @@
 use futures::{Stream, stream};
-use rquest::{Response, StatusCode};
+use rquest::{
+    Response, StatusCode,
+    header::{CONTENT_ENCODING, CONTENT_TYPE, HeaderMap, RETRY_AFTER},
+};
 use serde::{Deserialize, Serialize};
 use serde_json::{Value, json};
 use std::{convert::Infallible, fmt::Display};
@@ -23,8 +26,8 @@ pub enum ClewdrError {
     CookieDispatchError(#[from] oneshot::error::RecvError),
     #[error("Tokio mpsc send error: {0}")]
     CookieReqError(#[from] SendError<oneshot::Sender<Result<CookieStatus, ClewdrError>>>),
-    #[error("No cookie available")]
-    NoCookieAvailable,
+    #[error("No cookie available{}", retry_hint(.0))]
+    NoCookieAvailable(Option<i64>),
     #[error("Invalid Cookie, reason: {0}")]
     InvalidCookie(Reason),
     #[error("Json error: {0}")]
@@ -49,6 +52,31 @@ pub enum ClewdrError {
     PathNotFound(String),
     #[error("Invalid timestamp: {0}")]
     TimestampError(i64),
+    #[error("Request cancelled")]
+    Cancelled,
+    #[error("Request timed out")]
+    Timeout,
+    #[error("Unsupported media, only jpeg, png, gif, webp and pdf are accepted: {0}")]
+    UnsupportedMedia(String),
+    #[error("Image of {0} bytes exceeds the limit of {1} bytes")]
+    ImageTooLarge(usize, usize),
+    #[error("Upstream rejected the request body, see the server log for details")]
+    BadRequestBody,
+    #[error("Upstream returned an HTML page, status: {0}")]
+    HtmlResponse(StatusCode),
+    #[error("Upstream response is still {0} encoded, the decoder is missing")]
+    UndecodedResponse(String),
+    #[error("Startup self-test failed: {0}")]
+    SelfTestFailed(String),
+}
+
+/// Hint when the soonest cooling down cookie will be available
+fn retry_hint(reset: &Option<i64>) -> String {
+    let Some(reset) = reset else {
+        return String::new();
+    };
+    let minutes = (reset - chrono::Utc::now().timestamp()).max(0) / 60 + 1;
+    format!(", retry in {} minutes", minutes)
 }
 
 /// HTTP error response
@@ -108,14 +136,86 @@ impl Display for InnerHttpError {
     }
 }
 
+/// Start of the rate limit message returned when the cookie is used by another session
+const OVERLAP_MESSAGE: &str = "number of concurrent connections has exceeded your rate limit";
+
+/// Check if an error response says the cookie is used by another session at the same time
+fn is_overlap(status: StatusCode, error: &InnerHttpError) -> bool {
+    status == StatusCode::TOO_MANY_REQUESTS
+        && error.r#type == "rate_limit_error"
+        && error
+            .message
+            .as_str()
+            .is_some_and(|m| m.to_lowercase().starts_with(OVERLAP_MESSAGE))
+}
+
+/// Markers of a Cloudflare challenge page
+const CHALLENGE_KEYWORDS: [&str; 3] = ["challenge-platform", "cf-chl", "just a moment"];
+
+/// Classify an HTML response, a challenge benches the cookie
+async fn html_err(res: Response) -> ClewdrError {
+    let status = res.status();
+    let mitigated = res
+        .headers()
+        .get("cf-mitigated")
+        .is_some_and(|v| v.as_bytes() == b"challenge");
+    let body = res.text().await.unwrap_or_default().to_lowercase();
+    if mitigated || CHALLENGE_KEYWORDS.iter().any(|k| body.contains(k)) {
+        error!("Cloudflare challenge detected, status: {}", status);
+        return ClewdrError::InvalidCookie(Reason::Challenge);
+    }
+    error!("HTML error page received, status: {}", status);
+    ClewdrError::HtmlResponse(status)
+}
+
+/// Timestamp from the `retry-after` header, given in seconds or as an HTTP date
+fn retry_after(res: &Response) -> Option<i64> {
+    let value = res.headers().get(RETRY_AFTER)?.to_str().ok()?.trim();
+    if let Ok(secs) = value.parse::<i64>() {
+        return Some(chrono::Utc::now().timestamp() + secs.max(0));
+    }
+    chrono::DateTime::parse_from_rfc2822(value)
+        .ok()
+        .map(|t| t.timestamp())
+}
+
+/// Encoding of a response body the client did not decode
+/// The client removes `Content-Encoding` once it decoded the body
+fn undecoded_encoding(headers: &HeaderMap) -> Option<String> {
+    let encoding = headers.get(CONTENT_ENCODING)?.to_str().ok()?.trim();
+    if encoding.is_empty() || encoding.eq_ignore_ascii_case("identity") {
+        return None;
+    }
+    Some(encoding.to_string())
+}
+
 /// Check response from Claude Web
 pub async fn check_res_err(res: Response) -> Result<Response, ClewdrError> {
     let status = res.status();
+    if let Some(encoding) = undecoded_encoding(res.headers()) {
+        error!("Response body is still {} encoded", encoding);
+        return Err(ClewdrError::UndecodedResponse(encoding));
+    }
+    let html = res
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.contains("text/html"));
+    if html {
+        return Err(html_err(res).await);
+    }
     if status.is_success() {
         return Ok(res);
     }
     debug!("Error response status: {}", status);
+    let retry_after = retry_after(&res);
     let Ok(err) = res.json::<HttpError>().await else {
+        if status == StatusCode::TOO_MANY_REQUESTS
+            && let Some(time) = retry_after
+        {
+            error!("Rate limit exceeded, retry after {}", time);
+            return Err(ClewdrError::InvalidCookie(Reason::TooManyRequest(time)));
+        }
         let inner = InnerHttpError {
             message: json!("Failed to parse error response"),
             r#type: "error".to_string(),
@@ -128,6 +228,11 @@ pub async fn check_res_err(res: Response) -> Result<Response, ClewdrError> {
     };
     let err_clone = err.clone();
     let inner_error = err.error;
+    // check if the cookie is used by another session at the same time
+    if is_overlap(status, &inner_error) {
+        error!("Cookie overlap detected: {}", inner_error.message);
+        return Err(ClewdrError::InvalidCookie(Reason::Overlap));
+    }
     // check if the error is a rate limit error
     if status == 429 {
         // get the reset time from the error message
@@ -141,6 +246,11 @@ pub async fn check_res_err(res: Response) -> Result<Response, ClewdrError> {
             error!("Rate limit exceeded, expires in {} hours", hours);
             return Err(ClewdrError::InvalidCookie(Reason::TooManyRequest(time)));
         }
+        // fall back to the header when the body has no reset time
+        if let Some(time) = retry_after {
+            error!("Rate limit exceeded, retry after {}", time);
+            return Err(ClewdrError::InvalidCookie(Reason::TooManyRequest(time)));
+        }
     }
     Err(ClewdrError::OtherHttpError(status, err_clone))
 }
@@ -194,4 +304,171 @@ impl ClewdrError {
     pub fn error_body(&self) -> Message {
         non_stream_message(self.to_string())
     }
+
+    /// HTTP status of the error, for clients that check it
+    pub fn status_code(&self) -> StatusCode {
+        match self {
+            ClewdrError::NoCookieAvailable(_) => StatusCode::SERVICE_UNAVAILABLE,
+            ClewdrError::UnsupportedMedia(_) => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            ClewdrError::ImageTooLarge(..) => StatusCode::PAYLOAD_TOO_LARGE,
+            ClewdrError::Timeout => StatusCode::GATEWAY_TIMEOUT,
+            // upstream failed or rejected the request
+            ClewdrError::InvalidCookie(_)
+            | ClewdrError::RquestError(_)
+            | ClewdrError::OtherHttpError(..)
+            | ClewdrError::HtmlResponse(_)
+            | ClewdrError::UndecodedResponse(_)
+            | ClewdrError::BadRequestBody => StatusCode::BAD_GATEWAY,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    /// Error object in the OpenAI error schema
+    pub fn openai_error_body(&self) -> Value {
+        let status = self.status_code();
+        let r#type = if status.is_client_error() {
+            "invalid_request_error"
+        } else {
+            "api_error"
+        };
+        json!({
+            "error": {
+                "message": self.to_string(),
+                "type": r#type,
+                "code": status.as_u16(),
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::response::{IntoResponse, Response as AxumResponse};
+
+    use super::*;
+    use crate::testing::Upstream;
+
+    fn inner(body: &str) -> InnerHttpError {
+        serde_json::from_str::<HttpError>(body).unwrap().error
+    }
+
+    #[test]
+    fn undecoded_body_detected() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(undecoded_encoding(&headers), None);
+        headers.insert(CONTENT_ENCODING, "identity".parse().unwrap());
+        assert_eq!(undecoded_encoding(&headers), None);
+        headers.insert(CONTENT_ENCODING, "gzip".parse().unwrap());
+        assert_eq!(undecoded_encoding(&headers).as_deref(), Some("gzip"));
+    }
+
+    #[test]
+    fn overlap_detected() {
+        let err = inner(
+            r#"{"type":"error","error":{"type":"rate_limit_error","message":"Number of concurrent connections has exceeded your rate limit. Please try again later."}}"#,
+        );
+        assert!(is_overlap(StatusCode::TOO_MANY_REQUESTS, &err));
+    }
+
+    #[test]
+    fn other_errors_are_not_overlap() {
+        let conflict = inner(
+            r#"{"type":"error","error":{"type":"invalid_request_error","message":"Conversation already exists"}}"#,
+        );
+        assert!(!is_overlap(StatusCode::CONFLICT, &conflict));
+        let mention = inner(
+            r#"{"type":"error","error":{"type":"invalid_request_error","message":"Prompt mentions concurrent overlap"}}"#,
+        );
+        assert!(!is_overlap(StatusCode::BAD_REQUEST, &mention));
+        let limit = inner(
+            r#"{"type":"error","error":{"type":"rate_limit_error","message":"{\"resetsAt\":1700000000}"}}"#,
+        );
+        assert!(!is_overlap(StatusCode::TOO_MANY_REQUESTS, &limit));
+    }
+
+    /// Check a response served by the mock upstream
+    async fn checked(reply: fn() -> AxumResponse) -> Result<Response, ClewdrError> {
+        let upstream = Upstream::start(move |_| async move { reply() }).await;
+        let res = rquest::Client::new()
+            .post(format!("{}/completion", upstream.url))
+            .send()
+            .await
+            .unwrap();
+        check_res_err(res).await
+    }
+
+    fn reset_of(res: Result<Response, ClewdrError>) -> i64 {
+        match res {
+            Err(ClewdrError::InvalidCookie(Reason::TooManyRequest(t))) => t,
+            other => panic!("not a rate limit: {:?}", other.err()),
+        }
+    }
+
+    #[tokio::test]
+    async fn retry_after_seconds() {
+        let now = chrono::Utc::now().timestamp();
+        let reset = reset_of(
+            checked(|| {
+                (
+                    StatusCode::TOO_MANY_REQUESTS,
+                    [(RETRY_AFTER, "120")],
+                    "slow down",
+                )
+                    .into_response()
+            })
+            .await,
+        );
+        assert!((now + 120..=now + 122).contains(&reset));
+    }
+
+    #[tokio::test]
+    async fn retry_after_http_date() {
+        let reset = reset_of(
+            checked(|| {
+                (
+                    StatusCode::TOO_MANY_REQUESTS,
+                    [(RETRY_AFTER, "Wed, 21 Oct 2015 07:28:00 GMT")],
+                    "slow down",
+                )
+                    .into_response()
+            })
+            .await,
+        );
+        assert_eq!(reset, 1445412480);
+    }
+
+    #[tokio::test]
+    async fn resets_at_in_body_wins() {
+        // sample 429 from claude.ai, the message is a JSON string
+        let reset = reset_of(
+            checked(|| {
+                (
+                    StatusCode::TOO_MANY_REQUESTS,
+                    [(RETRY_AFTER, "60")],
+                    axum::Json(json!({
+                        "type": "error",
+                        "error": {
+                            "type": "rate_limit_error",
+                            "message": "{\"type\":\"exceeded_limit\",\"resetsAt\":1700000000,\"remaining\":null,\"perModelLimit\":false}",
+                        },
+                    })),
+                )
+                    .into_response()
+            })
+            .await,
+        );
+        assert_eq!(reset, 1700000000);
+    }
+
+    #[tokio::test]
+    async fn rate_limit_without_reset_is_http_error() {
+        let res = checked(|| StatusCode::TOO_MANY_REQUESTS.into_response()).await;
+        assert!(matches!(
+            res,
+            Err(ClewdrError::OtherHttpError(
+                StatusCode::TOO_MANY_REQUESTS,
+                _
+            ))
+        ));
+    }
 }
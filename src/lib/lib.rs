@@ -3,15 +3,24 @@ use std::sync::LazyLock;
 use clap::Parser;
 use figlet_rs::FIGfont;
 
+pub mod admin;
 pub mod bootstrap;
+pub mod cancel;
 pub mod client;
+pub mod complete;
 pub mod config;
 pub mod cookie;
 pub mod error;
+pub mod logging;
 pub mod messages;
+pub mod metrics;
+pub mod openai;
 pub mod router;
 pub mod state;
+pub mod stream;
 pub mod submit;
+#[cfg(test)]
+mod testing;
 pub mod text;
 pub mod types;
 pub mod utils;
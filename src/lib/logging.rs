@@ -0,0 +1,47 @@
+use tracing::{Level, Subscriber};
+use tracing_subscriber::{filter::dynamic_filter_fn, layer::Filter, registry::LookupSpan};
+
+use crate::messages::DEBUG_SPAN;
+
+/// Target of the prompt and response dumps, only logged in the debug span
+pub const DUMP_TARGET: &str = "clewdr::dump";
+
+/// Environment variable choosing the log format
+pub const LOG_FORMAT_ENV: &str = "CLEWDR_LOG_FORMAT";
+
+/// Format of log lines
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LogFormat {
+    /// Human readable lines
+    #[default]
+    Text,
+    /// One JSON object per line, for log aggregators
+    /// Events carry the fields of the request span, like `cookie` and `conv_uuid`
+    Json,
+}
+
+impl LogFormat {
+    /// Read the format from `CLEWDR_LOG_FORMAT`, text unless it is `json`
+    /// Logging starts before the config is loaded, so only the environment is used
+    pub fn from_env() -> Self {
+        match std::env::var(LOG_FORMAT_ENV) {
+            Ok(v) if v.trim().eq_ignore_ascii_case("json") => LogFormat::Json,
+            _ => LogFormat::Text,
+        }
+    }
+}
+
+/// Filter logging events up to `level`, and all events of requests in the debug span
+/// Pipeline dumps are logged at debug level, so release builds keep them,
+/// but only for requests in the debug span
+pub fn request_filter<S>(level: Level) -> impl Filter<S>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    dynamic_filter_fn(move |meta, cx| {
+        let debug = cx
+            .lookup_current()
+            .is_some_and(|s| s.scope().any(|s| s.name() == DEBUG_SPAN));
+        debug || (meta.level() <= &level && meta.target() != DUMP_TARGET)
+    })
+}
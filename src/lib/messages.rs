@@ -1,26 +1,45 @@
-use std::{fmt::Debug, mem, sync::LazyLock};
+use std::{
+    fmt::Debug,
+    mem,
+    sync::{Arc, LazyLock, Mutex},
+};
 
 use axum::{
     Json,
     body::Body,
     extract::{FromRequestParts, State},
+    http::HeaderMap,
     response::{IntoResponse, Response},
 };
+use claude_tokenizer::count_tokens;
 use eventsource_stream::Eventsource;
-use rquest::{StatusCode, header::ACCEPT};
+use futures::StreamExt;
+use rquest::{
+    StatusCode,
+    header::{ACCEPT, AUTHORIZATION},
+};
 use scopeguard::defer;
 use serde::{Deserialize, Serialize};
 use serde_json::{Value, json};
-use tokio::spawn;
-use tracing::{debug, info, warn};
+use tokio::{select, spawn, time::Instant};
+use tracing::{Instrument, Span, debug, error, field, info, info_span, warn};
 
 use crate::{
+    cancel::{REQUEST_ID_HEADER, cancellable},
     client::AppendHeaders,
+    complete::LegacyCompletion,
+    config::{Reason, StreamDecision, mask_password},
     error::{ClewdrError, check_res_err},
-    state::AppState,
-    text::merge_sse,
-    types::message::{ContentBlock, ImageSource, Message, Role},
-    utils::{print_out_json, print_out_text},
+    metrics::METRICS,
+    openai::ChatCompletion,
+    state::{ApiFormat, AppState},
+    stream::{ClewdrTransformer, sse_response},
+    text::{
+        find_stop, merge_sse, prompt_hash, render_template, sanitize_control_chars,
+        strip_response_prefix,
+    },
+    types::message::{ContentBlock, ImageSource, Message, Role, StopReason},
+    utils::new_request_log_dir,
 };
 
 /// Exact test message send by SillyTavern
@@ -43,12 +62,12 @@ pub struct Attachment {
 }
 
 impl Attachment {
-    pub fn new(content: String) -> Self {
+    pub fn new(content: String, file_name: &str, file_type: &str) -> Self {
         Attachment {
             file_size: content.len() as u64,
             extracted_content: content,
-            file_name: "paste.txt".to_string(),
-            file_type: "txt".to_string(),
+            file_name: file_name.to_string(),
+            file_type: file_type.to_string(),
         }
     }
 }
@@ -63,14 +82,21 @@ pub struct RequestBody {
     pub rendering_mode: String,
     pub prompt: String,
     pub timezone: String,
+    /// Enforced on the response only, the web API does not take them
+    #[serde(skip)]
+    pub stop_sequences: Vec<String>,
     #[serde(skip)]
     pub images: Vec<ImageSource>,
+    /// Padding text and its token count, None if the prompt is not padded
+    #[serde(skip)]
+    pub padding: Option<(String, usize)>,
 }
 
 /// Request body sent from the client
-#[derive(Deserialize, Serialize, Debug)]
+#[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct ClientRequestBody {
-    pub max_tokens: u64,
+    #[serde(default)]
+    pub max_tokens: Option<u64>,
     pub messages: Vec<Message>,
     #[serde(default)]
     pub stop_sequences: Vec<String>,
@@ -90,7 +116,7 @@ pub struct ClientRequestBody {
 }
 
 /// Thinking mode in Claude API Request
-#[derive(Deserialize, Serialize, Debug)]
+#[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct Thinking {
     budget_tokens: u64,
     r#type: String,
@@ -104,65 +130,294 @@ impl FromRequestParts<AppState> for Auth {
         parts: &mut axum::http::request::Parts,
         state: &AppState,
     ) -> Result<Self, Self::Rejection> {
+        // OpenAI clients send the key as a bearer token
         let key = parts
             .headers
             .get("x-api-key")
             .and_then(|v| v.to_str().ok())
+            .or_else(|| {
+                parts
+                    .headers
+                    .get(AUTHORIZATION)
+                    .and_then(|v| v.to_str().ok())
+                    .and_then(|v| v.strip_prefix("Bearer "))
+            })
             .unwrap_or_default();
         if !state.config.auth(key) {
-            warn!("Invalid password: {}", key);
+            warn!("Invalid password: {}", mask_password(key));
             return Err(StatusCode::UNAUTHORIZED);
         }
         Ok(Auth(key.to_string()))
     }
 }
 
+/// Header to override `custom_h` for a single request
+pub const HUMAN_HEADER: &str = "X-Clewdr-Human";
+/// Header to override `custom_a` for a single request
+pub const ASSISTANT_HEADER: &str = "X-Clewdr-Assistant";
+/// Header to log a single request at all levels, with the prompt and response dumps
+pub const DEBUG_HEADER: &str = "X-Clewdr-Debug";
+/// Span of requests with the debug header
+pub const DEBUG_SPAN: &str = "clewdr_debug";
+
+/// Read a role label from request headers
+fn role_label(headers: &HeaderMap, name: &str) -> Option<String> {
+    let label = headers.get(name)?.to_str().ok()?;
+    let valid = valid_label(label);
+    if valid.is_none() {
+        warn!("Invalid role label in {}: {:?}", name, label);
+    }
+    valid
+}
+
+/// Trimmed label, None if it is empty or contains line breaks
+fn valid_label(label: &str) -> Option<String> {
+    let label = label.trim();
+    (!label.is_empty() && !label.contains(['\n', '\r'])).then(|| label.to_string())
+}
+
+/// Decide streaming from the body field and the `Accept` header
+fn stream_decision(headers: &HeaderMap, body: bool, decision: StreamDecision) -> bool {
+    let accept = headers
+        .get(ACCEPT)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or_default();
+    let sse = accept.contains("text/event-stream");
+    let json = accept.contains("application/json");
+    // a header accepting both says nothing
+    let header = (sse != json).then_some(sse);
+    match header {
+        Some(header) if header != body => {
+            debug!(
+                "Stream field {} conflicts with Accept header, using {:?}",
+                body, decision
+            );
+            match decision {
+                StreamDecision::Body => body,
+                StreamDecision::Accept => header,
+            }
+        }
+        _ => body,
+    }
+}
+
 /// Axum handler for the API messages
 pub async fn api_messages(
+    Auth(key): Auth,
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(p): Json<ClientRequestBody>,
+) -> Response {
+    let span = debug_span(&state, &key, &headers);
+    handle_messages(key, state, headers, p)
+        .instrument(span)
+        .await
+}
+
+/// Axum handler counting the input tokens of the assembled prompt
+/// Padding is included when it would be added to the request
+pub async fn api_count_tokens(
     Auth(_): Auth,
-    State(mut state): State<AppState>,
+    State(state): State<AppState>,
     Json(p): Json<ClientRequestBody>,
+) -> Json<Value> {
+    let tokens = state
+        .transform(p)
+        .map(|b| b.input_tokens())
+        .unwrap_or_default();
+    Json(json!({ "input_tokens": tokens }))
+}
+
+/// Axum handler returning the body that would be sent upstream, without sending it
+/// Only for the admin, as it reveals the custom prompt
+pub async fn api_preview(
+    Auth(key): Auth,
+    State(mut state): State<AppState>,
+    headers: HeaderMap,
+    Json(mut p): Json<ClientRequestBody>,
+) -> Result<Json<RequestBody>, StatusCode> {
+    if !state.config.is_admin(&key) {
+        warn!("Preview requires the password");
+        return Err(StatusCode::FORBIDDEN);
+    }
+    state.custom_h = role_label(&headers, HUMAN_HEADER);
+    state.custom_a = role_label(&headers, ASSISTANT_HEADER);
+    p.stream = stream_decision(&headers, p.stream, state.config.stream_decision);
+    state.transform(p).map(Json).ok_or(StatusCode::BAD_REQUEST)
+}
+
+/// Span to log the request at all levels, only for the admin
+pub fn debug_span(state: &AppState, key: &str, headers: &HeaderMap) -> Span {
+    if headers.contains_key(DEBUG_HEADER) && state.config.is_admin(key) {
+        info_span!(DEBUG_SPAN)
+    } else {
+        Span::none()
+    }
+}
+
+/// Handle the API messages request
+/// Events of the request carry the model, key, cookie id and conv_uuid of its span
+pub async fn handle_messages(
+    key: String,
+    state: AppState,
+    headers: HeaderMap,
+    p: ClientRequestBody,
+) -> Response {
+    let span = info_span!(
+        "request",
+        model = %p.model,
+        key = %state.config.key_label(&key).unwrap_or_default(),
+        cookie = field::Empty,
+        conv_uuid = field::Empty,
+    );
+    serve_messages(key, state, headers, p)
+        .instrument(span)
+        .await
+}
+
+/// Serve the API messages request in its span
+async fn serve_messages(
+    key: String,
+    mut state: AppState,
+    headers: HeaderMap,
+    mut p: ClientRequestBody,
 ) -> Response {
+    if !state.config.allows_model(&key, &p.model) {
+        warn!("Model {} is not allowed for this key", p.model);
+        return StatusCode::FORBIDDEN.into_response();
+    }
+    METRICS.record_model(&p.model, &state.config.models);
+    state.custom_h = role_label(&headers, HUMAN_HEADER);
+    state.custom_a = role_label(&headers, ASSISTANT_HEADER);
+    p.stream = stream_decision(&headers, p.stream, state.config.stream_decision);
+
     // Check if the request is a test message
-    if !p.stream && p.messages == vec![TEST_MESSAGE.clone()] {
+    if p.messages == vec![TEST_MESSAGE.clone()] {
         // respond with a test message
-        return Json(non_stream_message(
+        return state.canned_response(
             "Claude Reverse Proxy is working, please send a real message.".to_string(),
-        ))
-        .into_response();
+            p.stream,
+            p.model,
+        );
     }
 
     let stream = p.stream;
     let stopwatch = chrono::Utc::now();
-    info!(
-        "Request received, stream mode: {}, messages: {}, model: {}",
-        stream,
-        p.messages.len(),
-        p.model
-    );
+    let label = state.config.key_label(&key).unwrap_or_default();
+    info!(stream, messages = p.messages.len(), "Request received");
+
+    let request_id = headers
+        .get(REQUEST_ID_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .map(ToString::to_string)
+        .unwrap_or_else(|| uuid::Uuid::new_v4().to_string());
+    let guard = state.cancels.register(request_id, label);
+    if state.config.request_log {
+        state.log_dir = new_request_log_dir(state.config.request_log_keep);
+    }
+    let deadline = state.config.request_timeout().map(|t| Instant::now() + t);
+    state.deadline = deadline;
+
+    // wait for a slot, held until the response body is done
+    let permit = match state.limiter.clone().acquire_owned().await {
+        Ok(permit) => permit,
+        Err(e) => {
+            error!("Request limiter closed: {}", e);
+            return StatusCode::SERVICE_UNAVAILABLE.into_response();
+        }
+    };
 
     if let Err(e) = state.request_cookie().await {
-        return Json(e.error_body()).into_response();
+        let mut res = if state.format == ApiFormat::OpenAI {
+            (e.status_code(), Json(e.openai_error_body())).into_response()
+        } else {
+            (e.status_code(), Json(e.error_body())).into_response()
+        };
+        // tell the client when to retry
+        if let ClewdrError::NoCookieAvailable(Some(reset)) = e {
+            res.headers_mut().insert("X-RateLimit-Reset", reset.into());
+        }
+        return res;
     }
+    // the cookie in use when the request ends, it changes when switching cookies
+    let current = Arc::new(Mutex::new(state.cookie.clone()));
     let mut state_clone = state.clone();
+    let current_clone = current.clone();
     defer! {
         // ensure the cookie is returned
-        spawn(async move {
-            let dur = chrono::Utc::now().signed_duration_since(stopwatch);
-            info!(
-                "Request finished, elapsed time: {} seconds",
-                dur.num_seconds()
-            );
-            state_clone.return_cookie(None).await;
-        });
+        state_clone.cookie = current_clone.lock().unwrap().take();
+        spawn(
+            async move {
+                let dur = chrono::Utc::now().signed_duration_since(stopwatch);
+                info!(elapsed_secs = dur.num_seconds(), "Request finished");
+                state_clone.return_cookie(None).await;
+            }
+            .in_current_span(),
+        );
     }
+    let mut switches = 0;
+    let res = loop {
+        let res = select! {
+            res = async {
+                state.bootstrap().await?;
+                state.try_message(p.clone()).await
+            } => res,
+            _ = guard.cancelled() => Err(ClewdrError::Cancelled),
+            _ = sleep_until_deadline(deadline) => Err(ClewdrError::Timeout),
+        };
+        // retry rate limits and overloads with the next cookie
+        let reason = match res {
+            Err(ClewdrError::InvalidCookie(
+                ref r @ (Reason::TooManyRequest(_) | Reason::Overlap | Reason::Challenge),
+            )) => Some(r.clone()),
+            Err(ClewdrError::OtherHttpError(c, _)) if c.as_u16() == 529 => None,
+            res => break res,
+        };
+        if switches >= state.config.max_connections {
+            break res;
+        }
+        switches += 1;
+        if let Err(e) = state.delete_chat().await {
+            warn!("Failed to delete chat: {}", e);
+        }
+        let last = state
+            .cookie
+            .as_ref()
+            .map(|c| c.cookie.id())
+            .unwrap_or_default();
+        state.return_cookie(reason).await;
+        if let Err(e) = state.request_cookie().await {
+            warn!("No cookie to switch to after {}: {}", last, e);
+            *current.lock().unwrap() = None;
+            break res;
+        }
+        *current.lock().unwrap() = state.cookie.clone();
+        warn!(
+            cookie = %last,
+            next = %state
+                .cookie
+                .as_ref()
+                .map(|c| c.cookie.id())
+                .unwrap_or_default(),
+            switches,
+            max = state.config.max_connections,
+            "Upstream busy, switching cookie"
+        );
+    };
+    let result = match res {
+        Ok(_) => "ok",
+        Err(ClewdrError::Cancelled) => "cancelled",
+        Err(ClewdrError::Timeout) => "timeout",
+        Err(_) => "error",
+    };
+    METRICS.completions.inc(result);
     // check if request is successful
-    match state.bootstrap().await.and(state.try_message(p).await) {
+    match res {
         Ok(b) => {
             if let Err(e) = state.delete_chat().await {
                 warn!("Failed to delete chat: {}", e);
             }
-            b.into_response()
+            hold_until_done(cancellable(b, guard), permit)
         }
         Err(e) => {
             // delete chat after an error
@@ -170,25 +425,32 @@ pub async fn api_messages(
                 warn!("Failed to delete chat: {}", e);
             }
             warn!("Error: {}", e);
+            // the cookie is returned here with the reason, not again by the defer
+            *current.lock().unwrap() = None;
             // 429 error
             match e {
                 ClewdrError::InvalidCookie(ref r) => {
                     state.return_cookie(Some(r.clone())).await;
                 }
-                ClewdrError::OtherHttpError(c, e) => {
+                ClewdrError::OtherHttpError(c, ref e) if state.format != ApiFormat::OpenAI => {
                     state.return_cookie(None).await;
-                    return (c, Json(e)).into_response();
+                    return (c, Json(e.clone())).into_response();
                 }
                 _ => {
                     state.return_cookie(None).await;
                 }
             }
+            // clients rely on the status code
+            let status = e.status_code();
+            if state.format == ApiFormat::OpenAI {
+                return (status, Json(e.openai_error_body())).into_response();
+            }
             if stream {
                 // stream the error as a response
-                Body::from_stream(e.error_stream()).into_response()
+                (status, sse_response(Body::from_stream(e.error_stream()))).into_response()
             } else {
                 // return the error as a response
-                Json(e.error_body()).into_response()
+                (status, Json(e.error_body())).into_response()
             }
         }
     }
@@ -197,19 +459,281 @@ pub async fn api_messages(
 impl AppState {
     /// Try to send a message to the Claude API
     async fn try_message(&mut self, p: ClientRequestBody) -> Result<Response, ClewdrError> {
-        print_out_json(&p, "0.req.json");
+        self.log_json(&p, "0.req.json");
         let stream = p.stream;
-        let proxy = self.config.rquest_proxy.clone();
-        let Some(ref org_uuid) = self.org_uuid else {
-            return Ok(Json(non_stream_message(
+        if self.org_uuid.is_none() {
+            return Ok(self.canned_response(
                 "No organization found, please check your cookie.".to_string(),
-            ))
-            .into_response());
-        };
+                stream,
+                p.model,
+            ));
+        }
+
+        // wait a bit to mimic human timing
+        let delay = self.config.humanize_delay();
+        if !delay.is_zero() {
+            debug!("Humanize delay: {} ms", delay.as_millis());
+            tokio::time::sleep(delay).await;
+        }
+
+        // keep a copy for the shadow request
+        let shadow = (self.config.shadow_mode && !stream).then(|| p.clone());
 
         // Create a new conversation
+        let thinking = p.thinking.is_some();
+        let name = if self.config.hash_conversation_name {
+            prompt_hash(&p)
+        } else {
+            String::new()
+        };
+        self.create_conversation(&p.model, thinking, &name).await?;
+
+        // generate the request body
+        // check if the request is empty
+        let model = p.model.clone();
+        let Some(mut body) = self.transform(p) else {
+            return Ok(self.canned_response(
+                "Empty request, please send a message.".to_string(),
+                stream,
+                model,
+            ));
+        };
+
+        // check images
+        let images = mem::take(&mut body.images);
+
+        // upload images
+        let files = self.upload_images(images).await?;
+        body.files = files;
+
+        // send the request
+        self.record_prompt(&body);
+        self.log_json(&body, "4.req.json");
+        let api_res = self.send_completion(&body).await?;
+
+        // if not streaming, return the response
+        if !stream {
+            let stream = api_res.bytes_stream().eventsource();
+            let (mut text, mut truncated) = merge_sse(
+                stream,
+                self.config.soft_timeout(),
+                self.config.max_response_bytes,
+            )
+            .await;
+            // retry short refusals in a new conversation
+            let mut retries = 0;
+            while retries < self.config.max_refusal_retries && self.config.is_refusal(&text) {
+                retries += 1;
+                warn!(
+                    "Refusal detected, retrying {}/{}",
+                    retries, self.config.max_refusal_retries
+                );
+                if let Err(e) = self.delete_chat().await {
+                    warn!("Failed to delete chat: {}", e);
+                }
+                self.create_conversation(&body.model, thinking, &name)
+                    .await?;
+                let api_res = self.send_completion(&body).await?;
+                (text, truncated) = merge_sse(
+                    api_res.bytes_stream().eventsource(),
+                    self.config.soft_timeout(),
+                    self.config.max_response_bytes,
+                )
+                .await;
+            }
+            if let Some(end) = find_stop(&text, &body.stop_sequences) {
+                text.truncate(end);
+            }
+            if self.config.sanitize_control_chars {
+                text = sanitize_control_chars(&text);
+            }
+            let text = strip_response_prefix(&text, &self.config.strip_response_prefixes);
+            self.log_text(text, "non_stream.txt");
+            if let Some(shadow) = shadow {
+                self.spawn_shadow(shadow, text.to_string());
+            }
+            // a soft timeout or the size cap ends the text like running out of tokens
+            let (stop_reason, finish_reason) = if truncated {
+                (StopReason::MaxTokens, "max_tokens")
+            } else {
+                (StopReason::EndTurn, "end_turn")
+            };
+            match self.format {
+                ApiFormat::Legacy => {
+                    let res =
+                        LegacyCompletion::new(text.to_string(), Some(stop_reason), body.model);
+                    return Ok(Json(res).into_response());
+                }
+                ApiFormat::OpenAI => {
+                    let res = ChatCompletion::new(
+                        text.to_string(),
+                        stop_reason,
+                        body.model.clone(),
+                        body.input_tokens(),
+                    );
+                    return Ok(Json(res).into_response());
+                }
+                ApiFormat::Claude => {}
+            }
+            if let Some(ref template) = self.config.response_template {
+                let res = render_template(template, text, &body.model, finish_reason)?;
+                return Ok(Json(res).into_response());
+            }
+            return Ok(Json(non_stream_message(text.to_string())).into_response());
+        }
+
+        // stream the response
+        let transformer = ClewdrTransformer::new(self.config.stream_mode)
+            .with_buffer_size(self.config.stream_buffer_size)
+            .with_soft_timeout(self.config.soft_timeout())
+            .with_max_bytes(self.config.max_response_bytes)
+            .with_stop_sequences(body.stop_sequences.clone())
+            .with_sanitize(self.config.sanitize_control_chars)
+            .with_prefixes(self.config.strip_response_prefixes.clone())
+            .with_deadline(self.deadline)
+            .with_format(self.format, body.model);
+        if transformer.is_passthrough() {
+            let input_stream = api_res.bytes_stream();
+            return Ok(sse_response(Body::from_stream(
+                transformer.passthrough(input_stream),
+            )));
+        }
+        let input_stream = api_res.bytes_stream().eventsource();
+        Ok(sse_response(Body::from_stream(
+            transformer.transform_stream(input_stream),
+        )))
+    }
+
+    /// Reply without calling upstream, as SSE if the client asked for a stream
+    fn canned_response(&self, text: String, stream: bool, model: String) -> Response {
+        if !stream {
+            return Json(non_stream_message(text)).into_response();
+        }
+        let body = ClewdrTransformer::new(self.config.stream_mode)
+            .with_format(self.format, model)
+            .canned(text);
+        sse_response(Body::from(body))
+    }
+
+    /// Send the request again without padding on another cookie and log the divergence
+    /// Runs in the background, the client only gets the primary response
+    fn spawn_shadow(&self, p: ClientRequestBody, primary: String) {
+        let mut state = self.clone();
+        state.cookie = None;
+        state.org_uuid = None;
+        state.conv_uuid = None;
+        state.shadow = true;
+        spawn(async move {
+            if let Err(e) = state.request_cookie().await {
+                debug!("No cookie for shadow request: {}", e);
+                return;
+            }
+            let res = match state.bootstrap().await {
+                Ok(_) => state.merged_message(p).await,
+                Err(e) => Err(e),
+            };
+            if let Err(e) = state.delete_chat().await {
+                warn!("Failed to delete chat: {}", e);
+            }
+            match res {
+                Ok(text) => {
+                    let common = primary
+                        .chars()
+                        .zip(text.chars())
+                        .take_while(|(a, b)| a == b)
+                        .count();
+                    info!(
+                        "Shadow divergence: primary {} chars, shadow {} chars, common prefix {} chars",
+                        primary.chars().count(),
+                        text.chars().count(),
+                        common
+                    );
+                    state.log_text(&text, "shadow.txt");
+                    state.return_cookie(None).await;
+                }
+                Err(e) => {
+                    warn!("Shadow request failed: {}", e);
+                    let reason = match e {
+                        ClewdrError::InvalidCookie(r) => Some(r),
+                        _ => None,
+                    };
+                    state.return_cookie(reason).await;
+                }
+            }
+        });
+    }
+
+    /// Send one completion with the probe model through the whole pipeline
+    /// Fails unless the response is non-empty and not a refusal
+    pub async fn self_test(&self) -> Result<(), ClewdrError> {
+        let mut state = self.clone();
+        state.request_cookie().await?;
+        let p = ClientRequestBody {
+            max_tokens: Some(16),
+            messages: vec![TEST_MESSAGE.clone()],
+            stop_sequences: vec![],
+            model: self.config.probe_model.clone(),
+            stream: false,
+            thinking: None,
+            system: Value::Null,
+            temperature: 0.0,
+            top_p: 0.0,
+            top_k: 0,
+        };
+        let res = match state.bootstrap().await {
+            Ok(_) => state.merged_message(p).await,
+            Err(e) => Err(e),
+        };
+        if let Err(e) = state.delete_chat().await {
+            warn!("Failed to delete chat: {}", e);
+        }
+        let reason = match res {
+            Err(ClewdrError::InvalidCookie(ref r)) => Some(r.clone()),
+            _ => None,
+        };
+        state.return_cookie(reason).await;
+        let text = res.map_err(|e| ClewdrError::SelfTestFailed(e.to_string()))?;
+        if text.trim().is_empty() {
+            return Err(ClewdrError::SelfTestFailed("empty response".to_string()));
+        }
+        if self.config.is_refusal(&text) {
+            return Err(ClewdrError::SelfTestFailed(format!("refusal: {}", text)));
+        }
+        info!("Startup self-test passed: {}", text.trim());
+        Ok(())
+    }
+
+    /// Send the request in a new conversation and merge the response
+    async fn merged_message(&mut self, p: ClientRequestBody) -> Result<String, ClewdrError> {
+        self.create_conversation(&p.model, p.thinking.is_some(), "")
+            .await?;
+        let mut body = self.transform(p).ok_or(ClewdrError::UnexpectedNone)?;
+        let images = mem::take(&mut body.images);
+        body.files = self.upload_images(images).await?;
+        self.record_prompt(&body);
+        let api_res = self.send_completion(&body).await?;
+        let (text, _) = merge_sse(
+            api_res.bytes_stream().eventsource(),
+            self.config.soft_timeout(),
+            self.config.max_response_bytes,
+        )
+        .await;
+        Ok(text)
+    }
+
+    /// Create a new conversation and store its uuid
+    async fn create_conversation(
+        &mut self,
+        model: &str,
+        thinking: bool,
+        name: &str,
+    ) -> Result<(), ClewdrError> {
+        let org_uuid = self.org_uuid.as_ref().ok_or(ClewdrError::UnexpectedNone)?;
         let new_uuid = uuid::Uuid::new_v4().to_string();
+        Span::current().record("conv_uuid", new_uuid.as_str());
+        debug!("Creating conversation");
         self.conv_uuid = Some(new_uuid.to_string());
+        self.track_chat();
         let endpoint = format!(
             "{}/api/organizations/{}/chat_conversations",
             self.config.endpoint(),
@@ -217,76 +741,577 @@ impl AppState {
         );
         let mut body = json!({
             "uuid": new_uuid,
-            "name":""
+            "name": name
         });
 
         // enable thinking mode
-        if p.thinking.is_some() {
+        if thinking {
             body["paprika_mode"] = "extended".into();
-            body["model"] = p.model.clone().into();
+            body["model"] = model.into();
         }
         let api_res = self
             .client
             .post(endpoint)
             .json(&body)
-            .append_headers("", proxy.clone())
+            .append_headers("", self.config.rquest_proxy.clone())
             .send()
             .await?;
-        debug!("New conversation created: {}", new_uuid);
+        debug!(name, "New conversation created");
 
         check_res_err(api_res).await?;
+        Ok(())
+    }
 
-        // generate the request body
-        // check if the request is empty
-        let Some(mut body) = self.transform(p) else {
-            return Ok(Json(non_stream_message(
-                "Empty request, please send a message.".to_string(),
-            ))
-            .into_response());
-        };
-
-        // check images
-        let images = mem::take(&mut body.images);
-
-        // upload images
-        let files = self.upload_images(images).await;
-        body.files = files;
+    /// Record the padding and log the prompt of a request that is sent
+    /// Previews and token counts transform requests too, but send nothing
+    fn record_prompt(&self, body: &RequestBody) {
+        METRICS.record_padding(body.padding.as_ref().map(|(_, tokens)| *tokens));
+        if let Some((ref text, _)) = body.padding {
+            self.log_text(text, "padding.txt");
+        }
+        let paste = body
+            .attachments
+            .first()
+            .map_or(body.prompt.as_str(), |a| a.extracted_content.as_str());
+        self.log_text(paste, "paste.txt");
+    }
 
-        // send the request
-        print_out_json(&body, "4.req.json");
+    /// Send the completion request to the current conversation
+    async fn send_completion(&self, body: &RequestBody) -> Result<rquest::Response, ClewdrError> {
+        let org_uuid = self.org_uuid.as_ref().ok_or(ClewdrError::UnexpectedNone)?;
+        let conv_uuid = self.conv_uuid.as_ref().ok_or(ClewdrError::UnexpectedNone)?;
         let endpoint = format!(
             "{}/api/organizations/{}/chat_conversations/{}/completion",
             self.config.endpoint(),
             org_uuid,
-            new_uuid
+            conv_uuid
         );
 
-        let api_res = self
-            .client
-            .post(endpoint)
-            .json(&body)
-            .append_headers("", proxy)
-            .header_append(ACCEPT, "text/event-stream")
-            .send()
-            .await?;
+        let mut retries = 0;
+        loop {
+            let start = Instant::now();
+            let api_res = self
+                .client
+                .post(endpoint.clone())
+                .json(body)
+                .append_headers("", self.config.rquest_proxy.clone())
+                .header_append(ACCEPT, "text/event-stream")
+                .send()
+                .await?;
+            METRICS.record_upstream_latency(start.elapsed().as_millis() as u64);
+            match check_res_err(api_res).await {
+                // error pages are often transient
+                Err(ClewdrError::HtmlResponse(status)) if retries < self.config.html_retries => {
+                    retries += 1;
+                    warn!(
+                        "HTML response with status {}, retrying {}/{}",
+                        status, retries, self.config.html_retries
+                    );
+                }
+                // the assembled body is most likely at fault
+                Err(ClewdrError::OtherHttpError(StatusCode::BAD_REQUEST, e)) => {
+                    error!(
+                        "Upstream rejected the request body: {}\nBody: {}",
+                        e,
+                        body.masked()
+                    );
+                    return Err(ClewdrError::BadRequestBody);
+                }
+                res => return res,
+            }
+        }
+    }
+}
 
-        let api_res = check_res_err(api_res).await?;
+impl RequestBody {
+    /// Tokens of the prompt and attachments, as the model sees them
+    fn input_tokens(&self) -> usize {
+        self.attachments
+            .iter()
+            .map(|a| a.extracted_content.as_str())
+            .chain([self.prompt.as_str()])
+            .map(|t| count_tokens(t).unwrap_or_default())
+            .sum()
+    }
 
-        // if not streaming, return the response
-        if !stream {
-            let stream = api_res.bytes_stream().eventsource();
-            let text = merge_sse(stream).await;
-            print_out_text(&text, "non_stream.txt");
-            return Ok(Json(non_stream_message(text)).into_response());
+    /// Body with long texts truncated, safe to log
+    fn masked(&self) -> Value {
+        /// Truncate all long strings in a JSON value
+        fn truncate(value: &mut Value) {
+            match value {
+                Value::String(s) if s.chars().count() > 200 => {
+                    let len = s.chars().count();
+                    *s = format!(
+                        "{}... ({} chars)",
+                        s.chars().take(100).collect::<String>(),
+                        len
+                    );
+                }
+                Value::Array(a) => a.iter_mut().for_each(truncate),
+                Value::Object(o) => o.values_mut().for_each(truncate),
+                _ => {}
+            }
         }
+        let mut value = serde_json::to_value(self).unwrap_or_default();
+        truncate(&mut value);
+        value
+    }
+}
 
-        // stream the response
-        let input_stream = api_res.bytes_stream();
-        Ok(Body::from_stream(input_stream).into_response())
+/// Wait until the deadline, forever if there is none
+async fn sleep_until_deadline(deadline: Option<Instant>) {
+    match deadline {
+        Some(deadline) => tokio::time::sleep_until(deadline).await,
+        None => std::future::pending().await,
     }
 }
 
+/// Keep the value alive until the response body is finished or dropped
+fn hold_until_done<T: Send + 'static>(res: Response, value: T) -> Response {
+    let (parts, body) = res.into_parts();
+    let body = body.into_data_stream().map(move |chunk| {
+        let _ = &value;
+        chunk
+    });
+    Response::from_parts(parts, Body::from_stream(body))
+}
+
 /// Transform a string to a message
 pub fn non_stream_message(str: String) -> Message {
     Message::new_blocks(Role::Assistant, vec![ContentBlock::Text { text: str }])
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::Write,
+        sync::atomic::{AtomicUsize, Ordering},
+    };
+
+    use axum::http::{
+        HeaderValue,
+        header::{CONTENT_ENCODING, CONTENT_TYPE},
+    };
+    use flate2::{Compression, write::GzEncoder};
+    use tokio::sync::{mpsc, watch};
+    use tracing::Level;
+    use tracing_subscriber::{Layer, Registry, fmt, layer::SubscriberExt};
+
+    use super::*;
+    use crate::{
+        config::{Config, CookieInfo},
+        cookie::PoolStatus,
+        logging::request_filter,
+        testing::{self, Upstream, cookie},
+    };
+
+    fn state(config: Config) -> AppState {
+        let (req_tx, _) = mpsc::channel(1);
+        let (ret_tx, _) = mpsc::channel(1);
+        let (submit_tx, _) = mpsc::channel(1);
+        let (admin_tx, _) = mpsc::channel(1);
+        let (_, pool) = watch::channel(PoolStatus::default());
+        AppState::new(config, req_tx, ret_tx, submit_tx, admin_tx, pool)
+    }
+
+    /// Log lines written by a test subscriber
+    #[derive(Clone, Default)]
+    struct Logs(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Logs {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Logs {
+        fn text(&self) -> String {
+            String::from_utf8_lossy(&self.0.lock().unwrap()).into_owned()
+        }
+    }
+
+    #[test]
+    fn debug_header_dumps_only_its_request() {
+        let logs = Logs::default();
+        let writer = logs.clone();
+        let subscriber = Registry::default().with(
+            fmt::layer()
+                .with_writer(move || writer.clone())
+                .with_ansi(false)
+                .with_filter(request_filter(Level::INFO)),
+        );
+        let mut config = Config::default();
+        config.no_auth = true;
+        config.request_log = false;
+        let state = state(config);
+        let mut headers = HeaderMap::new();
+        headers.insert(DEBUG_HEADER, "1".parse().unwrap());
+        tracing::subscriber::with_default(subscriber, || {
+            debug_span(&state, "", &headers).in_scope(|| {
+                debug!("detail a");
+                state.log_text("prompt a", "paste.txt");
+            });
+            debug_span(&state, "", &HeaderMap::new()).in_scope(|| {
+                debug!("detail b");
+                state.log_text("prompt b", "paste.txt");
+            });
+        });
+        let logs = logs.text();
+        assert!(logs.contains("detail a"));
+        assert!(logs.contains("paste.txt:\nprompt a"));
+        assert!(!logs.contains("detail b"));
+        assert!(!logs.contains("prompt b"));
+    }
+
+    /// Current value of a metric line
+    fn metric(line: &str) -> u64 {
+        METRICS
+            .render()
+            .lines()
+            .find_map(|l| l.strip_prefix(line)?.trim().parse().ok())
+            .unwrap_or_default()
+    }
+
+    #[test]
+    fn padding_recorded_when_sent() {
+        let mut config = Config::default();
+        config.request_log = false;
+        config.padtxt_len = 100;
+        config.pad_tokens = (0..200).map(|i| format!("tok{i}")).collect();
+        let state = state(config);
+        let p: ClientRequestBody = serde_json::from_value(json!({
+            "model": "claude",
+            "messages": [{ "role": "user", "content": "hi" }],
+        }))
+        .unwrap();
+        let body = state.transform(p).unwrap();
+        let (_, tokens) = body.padding.clone().unwrap();
+        assert!(tokens > 100);
+
+        let padded = "clewdr_padding_requests_total{padded=\"true\"}";
+        let before = (metric(padded), metric("clewdr_padding_tokens_sum"));
+        state.record_prompt(&body);
+        // other tests may record too, the counters only grow
+        assert!(metric(padded) > before.0);
+        assert!(metric("clewdr_padding_tokens_sum") >= before.1 + tokens as u64);
+    }
+
+    fn request(text: &str) -> ClientRequestBody {
+        serde_json::from_value(json!({
+            "model": "claude",
+            "messages": [{ "role": "user", "content": text }],
+        }))
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn json_logs_carry_request_fields() {
+        let logs = Logs::default();
+        let writer = logs.clone();
+        let subscriber = Registry::default().with(
+            fmt::layer()
+                .json()
+                .with_current_span(true)
+                .with_writer(move || writer.clone()),
+        );
+        let _default = tracing::subscriber::set_default(subscriber);
+        let upstream = Upstream::start(|_| async { testing::sse(&["hello"]) }).await;
+        let state = testing::state(testing::config(&upstream, &[cookie('a')]));
+        handle_messages(String::new(), state, HeaderMap::new(), request("hi")).await;
+
+        let id = CookieInfo::from(cookie('a').as_str()).id();
+        let lines = logs
+            .text()
+            .lines()
+            .map(|l| serde_json::from_str::<Value>(l).unwrap())
+            .collect::<Vec<_>>();
+        let created = lines
+            .iter()
+            .find(|l| l["fields"]["message"] == "New conversation created")
+            .unwrap();
+        let span = &created["span"];
+        assert_eq!(span["name"], "request");
+        assert_eq!(span["model"], "claude");
+        assert_eq!(span["cookie"], id.as_str());
+        assert_eq!(span["conv_uuid"].as_str().unwrap().len(), 36);
+    }
+
+    /// Body of a preview with the headers, prompt and attachments as text
+    async fn preview(config: Config, headers: HeaderMap, p: ClientRequestBody) -> String {
+        let Json(body) = api_preview(Auth(String::new()), State(state(config)), headers, Json(p))
+            .await
+            .unwrap();
+        serde_json::to_string(&body).unwrap()
+    }
+
+    fn chat() -> ClientRequestBody {
+        serde_json::from_value(json!({
+            "model": "claude",
+            "system": "sys",
+            "messages": [
+                { "role": "user", "content": "hi" },
+                { "role": "assistant", "content": "hello" },
+                { "role": "user", "content": "bye" },
+            ],
+        }))
+        .unwrap()
+    }
+
+    fn labels_config() -> Config {
+        let mut config = Config::default();
+        config.no_auth = true;
+        config.request_log = false;
+        config.custom_h = Some("User".to_string());
+        config.custom_a = Some("Bot".to_string());
+        config
+    }
+
+    #[tokio::test]
+    async fn header_labels_prefix_merged_prompt() {
+        let mut headers = HeaderMap::new();
+        headers.insert(HUMAN_HEADER, "Alice".parse().unwrap());
+        headers.insert(ASSISTANT_HEADER, "Narrator".parse().unwrap());
+        let body = preview(labels_config(), headers, chat()).await;
+        assert!(body.contains(r"sys\n\nAlice: hi\n\nNarrator: hello\n\nAlice: bye"));
+        assert!(!body.contains("User:"));
+        assert!(!body.contains("Bot:"));
+    }
+
+    #[test]
+    fn label_with_line_break_invalid() {
+        assert_eq!(valid_label(" Alice "), Some("Alice".to_string()));
+        assert_eq!(valid_label("Ali\nce"), None);
+        assert_eq!(valid_label("Alice\r"), Some("Alice".to_string()));
+        assert_eq!(valid_label("A\r\nB"), None);
+        assert_eq!(valid_label("  "), None);
+    }
+
+    #[tokio::test]
+    async fn invalid_header_label_falls_back_to_config() {
+        let mut headers = HeaderMap::new();
+        headers.insert(HUMAN_HEADER, HeaderValue::from_static("  "));
+        headers.insert(ASSISTANT_HEADER, HeaderValue::from_static("Narrator"));
+        let body = preview(labels_config(), headers, chat()).await;
+        assert!(body.contains(r"sys\n\nUser: hi\n\nNarrator: hello\n\nUser: bye"));
+    }
+
+    #[tokio::test]
+    async fn exhausted_pool_reports_soonest_reset() {
+        let upstream = Upstream::start(|_| async { testing::sse(&["unused"]) }).await;
+        let mut config = testing::config(&upstream, &[cookie('a'), cookie('b')]);
+        let now = chrono::Utc::now().timestamp();
+        let soonest = now + 37 * 60 + 30;
+        config.cookie_array[0].reset_time = Some(now + 3 * 60 * 60);
+        config.cookie_array[1].reset_time = Some(soonest);
+        let state = testing::state(config);
+        let res = handle_messages(String::new(), state, HeaderMap::new(), request("hi")).await;
+
+        assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(res.headers()["X-RateLimit-Reset"], soonest.to_string());
+        let body = testing::body_text(res).await;
+        assert!(body.contains("retry in 38 minutes"), "{body}");
+        assert!(upstream.received().is_empty());
+    }
+
+    const CHALLENGE_PAGE: &str = "<!DOCTYPE html><html><head><title>Just a moment...</title></head>\
+        <body><script src=\"/cdn-cgi/challenge-platform/h/b/orchestrate/chl_page/v1\"></script>\
+        </body></html>";
+
+    #[tokio::test]
+    async fn html_challenge_benches_cookie_and_is_not_streamed() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counter = calls.clone();
+        let upstream = Upstream::start(move |r| {
+            let first = counter.fetch_add(1, Ordering::SeqCst) == 0;
+            async move {
+                if first {
+                    (
+                        StatusCode::FORBIDDEN,
+                        [(CONTENT_TYPE, "text/html; charset=UTF-8")],
+                        CHALLENGE_PAGE,
+                    )
+                        .into_response()
+                } else {
+                    testing::reply(&r, &["hello"])
+                }
+            }
+        })
+        .await;
+        let state = testing::state(testing::config(&upstream, &[cookie('a'), cookie('b')]));
+        let mut pool = state.pool.clone();
+        let mut p = request("hi");
+        p.stream = true;
+        let res = handle_messages(String::new(), state, HeaderMap::new(), p).await;
+
+        assert_eq!(res.status(), StatusCode::OK);
+        let body = testing::body_text(res).await;
+        assert!(body.contains("hello"));
+        assert!(!body.to_lowercase().contains("<html"));
+        assert!(!body.contains("challenge-platform"));
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+        let benched = pool.wait_for(|s| s.exhausted == 1).await.unwrap();
+        assert_eq!(benched.usable, 1);
+    }
+
+    #[test]
+    fn stream_decision_table() {
+        use StreamDecision::{Accept, Body};
+        const SSE: &str = "text/event-stream";
+        const JSON: &str = "application/json";
+        const BOTH: &str = "text/event-stream, application/json";
+        // (Accept header, body field, decision, expected)
+        let cases = [
+            (None, false, Body, false),
+            (None, true, Body, true),
+            (None, false, Accept, false),
+            (None, true, Accept, true),
+            (Some(SSE), true, Body, true),
+            (Some(SSE), true, Accept, true),
+            (Some(SSE), false, Body, false),
+            (Some(SSE), false, Accept, true),
+            (Some(JSON), false, Body, false),
+            (Some(JSON), false, Accept, false),
+            (Some(JSON), true, Body, true),
+            (Some(JSON), true, Accept, false),
+            (Some(BOTH), false, Accept, false),
+            (Some(BOTH), true, Accept, true),
+            (Some("*/*"), false, Accept, false),
+            (Some("*/*"), true, Accept, true),
+        ];
+        for (accept, body, decision, expected) in cases {
+            let mut headers = HeaderMap::new();
+            if let Some(accept) = accept {
+                headers.insert(ACCEPT, HeaderValue::from_static(accept));
+            }
+            assert_eq!(
+                stream_decision(&headers, body, decision),
+                expected,
+                "Accept {accept:?}, stream {body}, {decision:?}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn shadow_mode_returns_only_primary() {
+        // only the primary request is padded
+        let upstream = Upstream::start(|r| async move {
+            if r.body.to_string().contains("tok1") {
+                testing::sse(&["primary answer"])
+            } else {
+                testing::sse(&["shadow answer"])
+            }
+        })
+        .await;
+        let mut config = testing::config(&upstream, &[cookie('a'), cookie('b')]);
+        config.shadow_mode = true;
+        config.padtxt_len = 100;
+        config.pad_tokens = (0..200).map(|i| format!("tok{i}")).collect();
+        let state = testing::state(config);
+        let res = handle_messages(String::new(), state, HeaderMap::new(), request("hi")).await;
+        let body = testing::body_text(res).await;
+        assert!(body.contains("primary answer"));
+        assert!(!body.contains("shadow answer"));
+
+        let completions = || {
+            upstream
+                .received()
+                .into_iter()
+                .filter(|r| r.path.ends_with("/completion"))
+                .collect::<Vec<_>>()
+        };
+        let wait = async {
+            while completions().len() < 2 {
+                tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+            }
+        };
+        tokio::time::timeout(std::time::Duration::from_secs(5), wait)
+            .await
+            .expect("no shadow request");
+        let completions = completions();
+        assert_eq!(completions.len(), 2);
+        let padded = completions
+            .iter()
+            .filter(|r| r.body.to_string().contains("tok1"))
+            .count();
+        assert_eq!(padded, 1);
+        let cookies = completions
+            .iter()
+            .map(|r| r.headers["cookie"].clone())
+            .collect::<Vec<_>>();
+        assert_ne!(cookies[0], cookies[1]);
+    }
+
+    #[tokio::test]
+    async fn rejected_body_logged_masked() {
+        let logs = Logs::default();
+        let writer = logs.clone();
+        let subscriber = Registry::default().with(
+            fmt::layer()
+                .with_writer(move || writer.clone())
+                .with_ansi(false)
+                .with_filter(request_filter(Level::INFO)),
+        );
+        let _default = tracing::subscriber::set_default(subscriber);
+        let upstream = Upstream::start(|_| async {
+            let error = json!({
+                "type": "error",
+                "error": { "type": "invalid_request_error", "message": "Invalid request" },
+            });
+            (StatusCode::BAD_REQUEST, Json(error)).into_response()
+        })
+        .await;
+        let state = testing::state(testing::config(&upstream, &[cookie('a')]));
+        let prompt = "word ".repeat(100) + "SECRET_TAIL";
+        let res = handle_messages(String::new(), state, HeaderMap::new(), request(&prompt)).await;
+
+        assert_eq!(res.status(), StatusCode::BAD_GATEWAY);
+        let logs = logs.text();
+        let line = logs
+            .lines()
+            .skip_while(|l| !l.contains("Upstream rejected the request body"))
+            .take(2)
+            .collect::<String>();
+        assert!(line.contains("Invalid request"));
+        assert!(line.contains(r#""model":"claude""#));
+        assert!(line.contains("chars)"));
+        assert!(!logs.contains("SECRET_TAIL"));
+    }
+
+    #[tokio::test]
+    async fn gzipped_stream_decoded_to_clean_chunks() {
+        let upstream = Upstream::start(|_| async {
+            let mut gz = GzEncoder::new(vec![], Compression::default());
+            gz.write_all(testing::messages_body(&["Hello", " there"]).as_bytes())
+                .unwrap();
+            let headers = [
+                (CONTENT_TYPE, "text/event-stream"),
+                (CONTENT_ENCODING, "gzip"),
+            ];
+            (headers, gz.finish().unwrap()).into_response()
+        })
+        .await;
+        let state = testing::state(testing::config(&upstream, &[cookie('a')]));
+        let mut p = request("hi");
+        p.stream = true;
+        let res = handle_messages(String::new(), state, HeaderMap::new(), p).await;
+
+        assert_eq!(res.status(), StatusCode::OK);
+        let body = testing::body_text(res).await;
+        let events = body
+            .lines()
+            .filter_map(|l| l.strip_prefix("data: "))
+            .map(|d| serde_json::from_str::<Value>(d).expect("garbled chunk"))
+            .collect::<Vec<_>>();
+        let text = events
+            .iter()
+            .filter_map(|e| e["delta"]["text"].as_str())
+            .collect::<String>();
+        assert_eq!(text, "Hello there");
+        assert_eq!(events.last().unwrap()["type"], "message_stop");
+    }
+}
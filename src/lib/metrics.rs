@@ -0,0 +1,266 @@
+use std::{
+    collections::HashMap,
+    fmt::Write,
+    sync::{
+        LazyLock, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+};
+
+use crate::config::Reason;
+
+/// Process-wide metrics, rendered in Prometheus text format
+pub static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::default);
+
+/// Upper bounds of the padding size buckets, in tokens
+const PADDING_BUCKETS: [u64; 6] = [1000, 2000, 4000, 8000, 16000, 32000];
+
+/// Upper bounds of the upstream latency buckets, in milliseconds
+const LATENCY_BUCKETS: [u64; 8] = [100, 250, 500, 1000, 2500, 5000, 10000, 30000];
+
+/// Counter split by the value of one label
+#[derive(Default)]
+pub struct LabeledCounter(Mutex<HashMap<String, u64>>);
+
+impl LabeledCounter {
+    /// Increment the counter of the label value
+    pub fn inc(&self, value: &str) {
+        *self.0.lock().unwrap().entry(value.to_string()).or_default() += 1;
+    }
+
+    fn render(&self, name: &str, help: &str, label: &str, out: &mut String) {
+        writeln!(out, "# HELP {name} {help}").unwrap();
+        writeln!(out, "# TYPE {name} counter").unwrap();
+        let map = self.0.lock().unwrap();
+        let mut values = map.iter().collect::<Vec<_>>();
+        values.sort();
+        for (value, count) in values {
+            let value = escape_label(value);
+            writeln!(out, "{name}{{{label}=\"{value}\"}} {count}").unwrap();
+        }
+    }
+}
+
+/// Escape a label value, it may come from the client
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Cumulative histogram with fixed buckets
+pub struct Histogram {
+    bounds: &'static [u64],
+    counts: Vec<AtomicU64>,
+    sum: AtomicU64,
+    count: AtomicU64,
+}
+
+impl Histogram {
+    fn new(bounds: &'static [u64]) -> Self {
+        Self {
+            bounds,
+            counts: bounds.iter().map(|_| AtomicU64::new(0)).collect(),
+            sum: AtomicU64::new(0),
+            count: AtomicU64::new(0),
+        }
+    }
+
+    /// Record a value
+    pub fn observe(&self, value: u64) {
+        self.bounds
+            .iter()
+            .zip(&self.counts)
+            .filter(|(b, _)| value <= **b)
+            .for_each(|(_, c)| {
+                c.fetch_add(1, Ordering::Relaxed);
+            });
+        self.sum.fetch_add(value, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn render(&self, name: &str, help: &str, out: &mut String) {
+        writeln!(out, "# HELP {name} {help}").unwrap();
+        writeln!(out, "# TYPE {name} histogram").unwrap();
+        for (bound, count) in self.bounds.iter().zip(&self.counts) {
+            let count = count.load(Ordering::Relaxed);
+            writeln!(out, "{name}_bucket{{le=\"{bound}\"}} {count}").unwrap();
+        }
+        let count = self.count.load(Ordering::Relaxed);
+        writeln!(out, "{name}_bucket{{le=\"+Inf\"}} {count}").unwrap();
+        writeln!(out, "{name}_sum {}", self.sum.load(Ordering::Relaxed)).unwrap();
+        writeln!(out, "{name}_count {count}").unwrap();
+    }
+}
+
+/// Application metrics
+pub struct Metrics {
+    padded_requests: AtomicU64,
+    unpadded_requests: AtomicU64,
+    padding_tokens: Histogram,
+    /// Finished requests by result
+    pub completions: LabeledCounter,
+    /// Requests by model, models not in the config count as `other`
+    model_requests: LabeledCounter,
+    /// Dispatches by cookie id, never the cookie itself
+    pub cookie_requests: LabeledCounter,
+    /// Cookies benched or dropped by reason
+    cookie_retirements: LabeledCounter,
+    upstream_latency: Histogram,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self {
+            padded_requests: AtomicU64::new(0),
+            unpadded_requests: AtomicU64::new(0),
+            padding_tokens: Histogram::new(&PADDING_BUCKETS),
+            completions: LabeledCounter::default(),
+            model_requests: LabeledCounter::default(),
+            cookie_requests: LabeledCounter::default(),
+            cookie_retirements: LabeledCounter::default(),
+            upstream_latency: Histogram::new(&LATENCY_BUCKETS),
+        }
+    }
+}
+
+impl Metrics {
+    /// Record the padding of a request, None if it was not padded
+    pub fn record_padding(&self, tokens: Option<usize>) {
+        match tokens {
+            Some(tokens) => {
+                self.padded_requests.fetch_add(1, Ordering::Relaxed);
+                self.padding_tokens.observe(tokens as u64);
+            }
+            None => {
+                self.unpadded_requests.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// Record a request for a model
+    /// Only configured models get their own label, so clients cannot grow the label set
+    pub fn record_model(&self, model: &str, models: &[String]) {
+        if models.iter().any(|m| m == model) {
+            self.model_requests.inc(model);
+        } else {
+            self.model_requests.inc("other");
+        }
+    }
+
+    /// Record a cookie taken out of rotation
+    pub fn record_retirement(&self, reason: &Reason) {
+        let reason = match reason {
+            Reason::NonPro => "non_pro",
+            Reason::Banned => "banned",
+            Reason::Null => "null",
+            Reason::Unverified => "unverified",
+            Reason::Restricted(_) => "restricted",
+            Reason::TooManyRequest(_) => "too_many_request",
+            Reason::Overlap => "overlap",
+            Reason::Challenge => "challenge",
+            Reason::Exhausted(_) => "exhausted",
+        };
+        self.cookie_retirements.inc(reason);
+    }
+
+    /// Record the time until upstream answered the completion request
+    pub fn record_upstream_latency(&self, millis: u64) {
+        self.upstream_latency.observe(millis);
+    }
+
+    /// Render all metrics in Prometheus text format
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        writeln!(
+            out,
+            "# HELP clewdr_padding_requests_total Requests by whether padding was added"
+        )
+        .unwrap();
+        writeln!(out, "# TYPE clewdr_padding_requests_total counter").unwrap();
+        for (padded, counter) in [
+            ("true", &self.padded_requests),
+            ("false", &self.unpadded_requests),
+        ] {
+            writeln!(
+                out,
+                "clewdr_padding_requests_total{{padded=\"{padded}\"}} {}",
+                counter.load(Ordering::Relaxed)
+            )
+            .unwrap();
+        }
+        self.padding_tokens.render(
+            "clewdr_padding_tokens",
+            "Padding tokens added per request",
+            &mut out,
+        );
+        self.completions.render(
+            "clewdr_completions_total",
+            "Finished requests by result",
+            "result",
+            &mut out,
+        );
+        self.model_requests.render(
+            "clewdr_model_requests_total",
+            "Requests by model",
+            "model",
+            &mut out,
+        );
+        self.cookie_requests.render(
+            "clewdr_cookie_requests_total",
+            "Dispatches by cookie id",
+            "cookie",
+            &mut out,
+        );
+        self.cookie_retirements.render(
+            "clewdr_cookie_retirements_total",
+            "Cookies benched or dropped by reason",
+            "reason",
+            &mut out,
+        );
+        self.upstream_latency.render(
+            "clewdr_upstream_latency_ms",
+            "Time until upstream answered the completion request",
+            &mut out,
+        );
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unknown_models_share_a_label() {
+        let metrics = Metrics::default();
+        let models = vec!["claude-sonnet-4-0".to_string()];
+        metrics.record_model("claude-sonnet-4-0", &models);
+        metrics.record_model("made-up-1", &models);
+        metrics.record_model("made-up-2", &models);
+        let out = metrics.render();
+        assert!(out.contains("clewdr_model_requests_total{model=\"claude-sonnet-4-0\"} 1"));
+        assert!(out.contains("clewdr_model_requests_total{model=\"other\"} 2"));
+        assert!(!out.contains("made-up"));
+    }
+
+    #[test]
+    fn label_values_are_escaped() {
+        assert_eq!(escape_label("a\"b\\c\nd"), "a\\\"b\\\\c\\nd");
+    }
+
+    #[test]
+    fn padding_size_bucketed() {
+        let metrics = Metrics::default();
+        metrics.record_padding(Some(1500));
+        metrics.record_padding(None);
+        let out = metrics.render();
+        assert!(out.contains("clewdr_padding_requests_total{padded=\"true\"} 1"));
+        assert!(out.contains("clewdr_padding_requests_total{padded=\"false\"} 1"));
+        assert!(out.contains("clewdr_padding_tokens_bucket{le=\"1000\"} 0"));
+        assert!(out.contains("clewdr_padding_tokens_bucket{le=\"2000\"} 1"));
+        assert!(out.contains("clewdr_padding_tokens_sum 1500"));
+        assert!(out.contains("clewdr_padding_tokens_count 1"));
+    }
+}
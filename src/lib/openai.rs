@@ -0,0 +1,417 @@
+use axum::{Json, extract::State, http::HeaderMap, response::Response};
+use claude_tokenizer::count_tokens;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tracing::{Instrument, warn};
+
+use crate::{
+    messages::{Auth, ClientRequestBody, debug_span, handle_messages},
+    state::{ApiFormat, AppState},
+    types::message::{ContentBlock, ImageSource, Message, Role, StopReason},
+};
+
+/// Request body of the OpenAI chat completion API
+#[derive(Deserialize, Serialize, Debug)]
+pub struct OpenAIRequestBody {
+    pub model: String,
+    pub messages: Vec<OpenAIMessage>,
+    #[serde(default)]
+    pub stream: bool,
+    #[serde(default)]
+    pub max_tokens: Option<u64>,
+    #[serde(default)]
+    pub max_completion_tokens: Option<u64>,
+    #[serde(default)]
+    pub stop: Option<Stop>,
+    #[serde(default)]
+    pub temperature: Option<f32>,
+    #[serde(default)]
+    pub top_p: Option<f32>,
+    /// Only one choice is ever returned
+    #[serde(default)]
+    pub n: Option<u32>,
+    /// Accepted for compatibility, not supported by Claude
+    #[serde(default)]
+    pub presence_penalty: Option<f32>,
+    /// Accepted for compatibility, not supported by Claude
+    #[serde(default)]
+    pub frequency_penalty: Option<f32>,
+}
+
+/// Stop sequences, a single string or a list
+#[derive(Deserialize, Serialize, Debug)]
+#[serde(untagged)]
+pub enum Stop {
+    One(String),
+    Many(Vec<String>),
+}
+
+/// Message of the OpenAI chat completion API
+#[derive(Deserialize, Serialize, Debug)]
+pub struct OpenAIMessage {
+    pub role: String,
+    #[serde(default)]
+    pub content: OpenAIContent,
+}
+
+/// Content of an OpenAI message, plain text or a list of parts
+#[derive(Deserialize, Serialize, Debug)]
+#[serde(untagged)]
+pub enum OpenAIContent {
+    Text(String),
+    Parts(Vec<OpenAIPart>),
+}
+
+impl Default for OpenAIContent {
+    fn default() -> Self {
+        OpenAIContent::Text(String::new())
+    }
+}
+
+/// Part of an OpenAI message
+#[derive(Deserialize, Serialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum OpenAIPart {
+    Text { text: String },
+    ImageUrl { image_url: ImageUrl },
+}
+
+/// Image of an OpenAI message, only data urls are supported
+#[derive(Deserialize, Serialize, Debug)]
+pub struct ImageUrl {
+    pub url: String,
+}
+
+impl OpenAIContent {
+    /// Text of the content, parts joined with new line
+    fn text(&self) -> String {
+        match self {
+            OpenAIContent::Text(text) => text.clone(),
+            OpenAIContent::Parts(parts) => parts
+                .iter()
+                .filter_map(|p| match p {
+                    OpenAIPart::Text { text } => Some(text.as_str()),
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+                .join("\n"),
+        }
+    }
+
+    /// Convert the content into Claude content blocks
+    fn into_blocks(self) -> Vec<ContentBlock> {
+        match self {
+            OpenAIContent::Text(text) => vec![ContentBlock::Text { text }],
+            OpenAIContent::Parts(parts) => parts
+                .into_iter()
+                .filter_map(|p| match p {
+                    OpenAIPart::Text { text } => Some(ContentBlock::Text { text }),
+                    OpenAIPart::ImageUrl { image_url } => {
+                        data_url_image(&image_url.url).map(|source| ContentBlock::Image { source })
+                    }
+                })
+                .collect(),
+        }
+    }
+}
+
+/// Parse a base64 data url into an image source
+fn data_url_image(url: &str) -> Option<ImageSource> {
+    let Some((media_type, data)) = url
+        .strip_prefix("data:")
+        .and_then(|u| u.split_once(";base64,"))
+    else {
+        warn!("Unsupported image url, only base64 data urls are accepted");
+        return None;
+    };
+    Some(ImageSource {
+        type_: "base64".to_string(),
+        media_type: media_type.to_string(),
+        data: data.to_string(),
+    })
+}
+
+impl From<OpenAIRequestBody> for ClientRequestBody {
+    /// System messages are merged into the system prompt, other roles are sent as user
+    fn from(value: OpenAIRequestBody) -> Self {
+        let (system, messages): (Vec<_>, Vec<_>) = value
+            .messages
+            .into_iter()
+            .partition(|m| matches!(m.role.as_str(), "system" | "developer"));
+        let system = system
+            .iter()
+            .map(|m| m.content.text())
+            .collect::<Vec<_>>()
+            .join("\n\n");
+        let messages = messages
+            .into_iter()
+            .map(|m| {
+                let role = match m.role.as_str() {
+                    "assistant" => Role::Assistant,
+                    _ => Role::User,
+                };
+                Message::new_blocks(role, m.content.into_blocks())
+            })
+            .collect();
+        let stop_sequences = match value.stop {
+            Some(Stop::One(s)) => vec![s],
+            Some(Stop::Many(s)) => s,
+            None => vec![],
+        };
+        ClientRequestBody {
+            max_tokens: value.max_completion_tokens.or(value.max_tokens),
+            messages,
+            stop_sequences,
+            model: value.model,
+            stream: value.stream,
+            thinking: None,
+            system: Value::String(system),
+            temperature: value.temperature.unwrap_or_default(),
+            top_p: value.top_p.unwrap_or_default(),
+            top_k: 0,
+        }
+    }
+}
+
+/// Reason the OpenAI API reports for the end of a choice
+fn finish_reason(reason: StopReason) -> &'static str {
+    match reason {
+        StopReason::MaxTokens => "length",
+        _ => "stop",
+    }
+}
+
+/// Response of the OpenAI chat completion API
+#[derive(Serialize, Debug)]
+pub struct ChatCompletion {
+    id: String,
+    object: &'static str,
+    created: i64,
+    model: String,
+    /// Always null, strict clients expect the field
+    system_fingerprint: Option<String>,
+    choices: Vec<Choice>,
+    usage: Usage,
+}
+
+/// Token usage, estimated with the local tokenizer as upstream does not report it
+#[derive(Serialize, Debug)]
+struct Usage {
+    prompt_tokens: usize,
+    completion_tokens: usize,
+    total_tokens: usize,
+}
+
+#[derive(Serialize, Debug)]
+struct Choice {
+    index: u32,
+    message: ChoiceMessage,
+    /// Always null, log probabilities are not available
+    logprobs: Option<Value>,
+    finish_reason: &'static str,
+}
+
+#[derive(Serialize, Debug)]
+struct ChoiceMessage {
+    role: &'static str,
+    content: String,
+}
+
+impl ChatCompletion {
+    pub fn new(
+        content: String,
+        stop_reason: StopReason,
+        model: String,
+        prompt_tokens: usize,
+    ) -> Self {
+        let completion_tokens = count_tokens(&content).unwrap_or_default();
+        ChatCompletion {
+            id: completion_id(),
+            object: "chat.completion",
+            created: chrono::Utc::now().timestamp(),
+            model,
+            system_fingerprint: None,
+            choices: vec![Choice {
+                index: 0,
+                message: ChoiceMessage {
+                    role: "assistant",
+                    content,
+                },
+                logprobs: None,
+                finish_reason: finish_reason(stop_reason),
+            }],
+            usage: Usage {
+                prompt_tokens,
+                completion_tokens,
+                total_tokens: prompt_tokens + completion_tokens,
+            },
+        }
+    }
+}
+
+/// Stream event of the OpenAI chat completion API
+#[derive(Serialize, Debug)]
+pub struct ChatCompletionChunk {
+    id: String,
+    object: &'static str,
+    created: i64,
+    model: String,
+    /// Always null, strict clients expect the field
+    system_fingerprint: Option<String>,
+    choices: Vec<ChunkChoice>,
+}
+
+#[derive(Serialize, Debug)]
+struct ChunkChoice {
+    index: u32,
+    delta: Delta,
+    /// Always null, log probabilities are not available
+    logprobs: Option<Value>,
+    finish_reason: Option<&'static str>,
+}
+
+/// Delta of a chunk, the role is only sent in the first one
+#[derive(Serialize, Debug, Default)]
+pub struct Delta {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub role: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub content: Option<String>,
+}
+
+impl ChatCompletionChunk {
+    pub fn new(id: &str, model: &str, delta: Delta, stop_reason: Option<StopReason>) -> Self {
+        ChatCompletionChunk {
+            id: id.to_string(),
+            object: "chat.completion.chunk",
+            created: chrono::Utc::now().timestamp(),
+            model: model.to_string(),
+            system_fingerprint: None,
+            choices: vec![ChunkChoice {
+                index: 0,
+                delta,
+                logprobs: None,
+                finish_reason: stop_reason.map(finish_reason),
+            }],
+        }
+    }
+}
+
+/// Generate an id for a chat completion
+pub fn completion_id() -> String {
+    format!("chatcmpl-{}", uuid::Uuid::new_v4().simple())
+}
+
+/// Model of the OpenAI model list
+#[derive(Serialize, Debug)]
+pub struct Model {
+    id: String,
+    object: &'static str,
+    owned_by: &'static str,
+}
+
+/// Response of the OpenAI model list API
+#[derive(Serialize, Debug)]
+pub struct ModelList {
+    object: &'static str,
+    data: Vec<Model>,
+}
+
+/// Axum handler listing the configured models the key may use
+pub async fn api_models(State(state): State<AppState>, Auth(key): Auth) -> Json<ModelList> {
+    let data = state
+        .config
+        .models
+        .iter()
+        .filter(|m| state.config.allows_model(&key, m))
+        .map(|m| Model {
+            id: m.clone(),
+            object: "model",
+            owned_by: "anthropic",
+        })
+        .collect();
+    Json(ModelList {
+        object: "list",
+        data,
+    })
+}
+
+/// Axum handler for the OpenAI chat completion API
+pub async fn api_openai(
+    Auth(key): Auth,
+    State(mut state): State<AppState>,
+    headers: HeaderMap,
+    Json(p): Json<OpenAIRequestBody>,
+) -> Response {
+    state.format = ApiFormat::OpenAI;
+    if p.n.is_some_and(|n| n > 1) {
+        warn!("Only one choice is supported, ignoring n = {:?}", p.n);
+    }
+    let span = debug_span(&state, &key, &headers);
+    handle_messages(key, state, headers, p.into())
+        .instrument(span)
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    /// Keys strict OpenAI clients require, even when null
+    fn assert_keys(value: &Value, keys: &[&str]) {
+        for key in keys {
+            assert!(value.get(key).is_some(), "missing {key} in {value}");
+        }
+    }
+
+    #[test]
+    fn completion_has_required_fields() {
+        let res = ChatCompletion::new("hi".to_string(), StopReason::MaxTokens, "m".into(), 3);
+        let value = serde_json::to_value(res).unwrap();
+        assert_keys(
+            &value,
+            &[
+                "id",
+                "object",
+                "created",
+                "model",
+                "system_fingerprint",
+                "choices",
+                "usage",
+            ],
+        );
+        let choice = &value["choices"][0];
+        assert_keys(choice, &["index", "message", "logprobs", "finish_reason"]);
+        assert_eq!(choice["index"], 0);
+        assert_eq!(choice["logprobs"], Value::Null);
+        assert_eq!(choice["finish_reason"], "length");
+        assert_eq!(value["usage"]["prompt_tokens"], 3);
+        assert_eq!(
+            value["usage"]["total_tokens"],
+            json!(3 + value["usage"]["completion_tokens"].as_u64().unwrap())
+        );
+    }
+
+    #[test]
+    fn chunk_has_required_fields() {
+        let chunk = ChatCompletionChunk::new("id", "m", Delta::default(), None);
+        let value = serde_json::to_value(chunk).unwrap();
+        assert_keys(
+            &value,
+            &[
+                "id",
+                "object",
+                "created",
+                "model",
+                "system_fingerprint",
+                "choices",
+            ],
+        );
+        assert_keys(
+            &value["choices"][0],
+            &["index", "delta", "logprobs", "finish_reason"],
+        );
+    }
+}
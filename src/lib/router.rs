@@ -1,15 +1,25 @@
 use axum::{
     Json, Router,
-    extract::Request,
-    http::HeaderMap,
-    response::Html,
-    routing::{options, post},
+    extract::{Request, State},
+    http::{HeaderMap, StatusCode, header::CONTENT_TYPE},
+    response::{Html, IntoResponse},
+    routing::{delete, get, options, post},
 };
 use const_format::{concatc, formatc};
-use serde_json::{Value, json};
-use tracing::{debug, error};
+use tracing::error;
 
-use crate::{messages::api_messages, state::AppState, submit::api_submit};
+use crate::{
+    admin::{api_add_cookies, api_list_cookies, api_remove_cookie},
+    cancel::api_cancel,
+    complete::api_complete,
+    cookie::PoolStatus,
+    error::ClewdrError,
+    messages::{api_count_tokens, api_messages, api_preview},
+    metrics::METRICS,
+    openai::{api_models, api_openai},
+    state::AppState,
+    submit::api_submit,
+};
 
 /// RouterBuilder for the application
 pub struct RouterBuilder {
@@ -23,9 +33,22 @@ impl RouterBuilder {
             inner: Router::new()
                 .route("/", options(api_options))
                 .route("/v1", options(api_options))
-                .route("/v1/chat/completions", post(reject_openai))
+                .route("/v1/chat/completions", post(api_openai))
+                .route("/v1/models", get(api_models))
                 .route("/v1/messages", post(api_messages))
+                .route("/v1/messages/count_tokens", post(api_count_tokens))
+                .route("/v1/preview", post(api_preview))
+                .route("/v1/complete", post(api_complete))
                 .route("/v1/submit", post(api_submit))
+                .route("/v1/cancel", post(api_cancel))
+                .route(
+                    "/admin/cookies",
+                    get(api_list_cookies).post(api_add_cookies),
+                )
+                .route("/admin/cookies/{index}", delete(api_remove_cookie))
+                .route("/metrics", get(api_metrics))
+                .route("/health", get(api_health))
+                .route("/readiness", get(api_readiness))
                 .fallback(api_fallback)
                 .with_state(state),
         }
@@ -37,16 +60,41 @@ impl RouterBuilder {
     }
 }
 
-/// Handle the OpenAI API request
-async fn reject_openai() -> Json<Value> {
-    debug!("Reject OpenAI API");
-    let response = json!({
-        "error": {
-            "message": "OpenAI API is not supported, please use as Claude Reverse Proxy. 请使用Claude反向代理而非OpenAI API兼容",
-            "type": "invalid_request_error",
-        }
-    });
-    Json(response)
+/// Router of the state, built only once the startup self-test passed if enabled
+/// A failing self-test leaves no router to serve, so the server never becomes ready
+pub async fn ready_router(state: AppState) -> Result<Router, ClewdrError> {
+    if state.config.startup_self_test {
+        println!(
+            "Running startup self-test with {}",
+            state.config.probe_model
+        );
+        state.self_test().await?;
+    }
+    Ok(RouterBuilder::new(state).build())
+}
+
+/// Liveness probe, the server is up if it answers
+async fn api_health() -> StatusCode {
+    StatusCode::OK
+}
+
+/// Readiness probe, unavailable when no cookie is usable
+async fn api_readiness(State(s): State<AppState>) -> (StatusCode, Json<PoolStatus>) {
+    let pool = *s.pool.borrow();
+    let status = if pool.usable > 0 {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (status, Json(pool))
+}
+
+/// Expose metrics in Prometheus text format
+async fn api_metrics() -> impl IntoResponse {
+    (
+        [(CONTENT_TYPE, "text/plain; version=0.0.4")],
+        METRICS.render(),
+    )
 }
 
 /// Handle the fallback request
@@ -102,11 +150,167 @@ async fn api_options() -> HeaderMap {
     headers.insert("Access-Control-Allow-Origin", "*".parse().unwrap());
     headers.insert(
         "Access-Control-Allow-Headers",
-        "Authorization, Content-Type".parse().unwrap(),
+        "Authorization, Content-Type, X-Api-Key, Anthropic-Version, Anthropic-Beta"
+            .parse()
+            .unwrap(),
     );
     headers.insert(
         "Access-Control-Allow-Methods",
-        "POST, GET, OPTIONS".parse().unwrap(),
+        "POST, GET, DELETE, OPTIONS".parse().unwrap(),
     );
     headers
 }
+
+#[cfg(test)]
+mod tests {
+    use axum::http::header::{CACHE_CONTROL, CONNECTION};
+    use rquest::Client;
+    use serde_json::json;
+
+    use super::*;
+    use crate::testing::{self, Upstream, cookie};
+
+    fn chat() -> serde_json::Value {
+        json!({
+            "model": "claude",
+            "messages": [{ "role": "user", "content": "hi" }],
+        })
+    }
+
+    #[tokio::test]
+    async fn read_only_rejects_mutations_and_serves_completions() {
+        let upstream = Upstream::start(|_| async { testing::sse(&["hello"]) }).await;
+        let config = testing::config(&upstream, &[cookie('a')]);
+        assert!(config.read_only);
+        let url = testing::serve(testing::state(config)).await;
+        let client = Client::new();
+
+        let add = client
+            .post(format!("{url}/admin/cookies"))
+            .json(&cookie('b'))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(add.status(), StatusCode::FORBIDDEN);
+        let remove = client
+            .delete(format!("{url}/admin/cookies/0"))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(remove.status(), StatusCode::FORBIDDEN);
+        let submit = client
+            .post(format!("{url}/v1/submit"))
+            .json(&json!({ "cookie": cookie('c') }))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(submit.status(), StatusCode::FORBIDDEN);
+        let list = client
+            .get(format!("{url}/admin/cookies"))
+            .send()
+            .await
+            .unwrap()
+            .json::<Vec<serde_json::Value>>()
+            .await
+            .unwrap();
+        assert_eq!(list.len(), 1);
+
+        let res = client
+            .post(format!("{url}/v1/messages"))
+            .json(&chat())
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert!(res.text().await.unwrap().contains("hello"));
+    }
+
+    #[tokio::test]
+    async fn no_auth_serves_requests_without_credentials() {
+        let upstream = Upstream::start(|_| async { testing::sse(&["hello"]) }).await;
+        let config = testing::config(&upstream, &[cookie('a')]);
+        assert!(config.no_auth);
+        let url = testing::serve(testing::state(config)).await;
+        let client = Client::new();
+
+        let res = client
+            .post(format!("{url}/v1/messages"))
+            .json(&chat())
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        let res = client
+            .post(format!("{url}/v1/chat/completions"))
+            .json(&chat())
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        let list = client
+            .get(format!("{url}/admin/cookies"))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(list.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn response_headers_match_stream_mode() {
+        let upstream = Upstream::start(|r| async move { testing::reply(&r, &["hello"]) }).await;
+        let config = testing::config(&upstream, &[cookie('a')]);
+        let url = testing::serve(testing::state(config)).await;
+        let client = Client::new();
+        let legacy = json!({ "model": "claude", "prompt": "hi" });
+        for (path, body) in [
+            ("/v1/messages", chat()),
+            ("/v1/chat/completions", chat()),
+            ("/v1/complete", legacy),
+        ] {
+            for stream in [false, true] {
+                let mut body = body.clone();
+                body["stream"] = json!(stream);
+                let res = client
+                    .post(format!("{url}{path}"))
+                    .json(&body)
+                    .send()
+                    .await
+                    .unwrap();
+                assert_eq!(res.status(), StatusCode::OK, "{path}");
+                let headers = res.headers();
+                if stream {
+                    assert_eq!(headers[CONTENT_TYPE], "text/event-stream", "{path}");
+                    assert_eq!(headers[CACHE_CONTROL], "no-cache", "{path}");
+                    assert_eq!(headers[CONNECTION], "keep-alive", "{path}");
+                } else {
+                    assert_eq!(headers[CONTENT_TYPE], "application/json", "{path}");
+                    let text = res.text().await.unwrap();
+                    assert!(serde_json::from_str::<serde_json::Value>(&text).is_ok());
+                }
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn failing_self_test_prevents_readiness() {
+        let upstream = Upstream::start(|_| async { testing::sse(&[""]) }).await;
+        let mut config = testing::config(&upstream, &[cookie('a')]);
+        config.startup_self_test = true;
+        let res = ready_router(testing::state(config)).await;
+        assert!(matches!(res, Err(ClewdrError::SelfTestFailed(_))));
+
+        let upstream = Upstream::start(|_| async { testing::sse(&["pong"]) }).await;
+        let mut config = testing::config(&upstream, &[cookie('a')]);
+        config.startup_self_test = true;
+        let router = ready_router(testing::state(config)).await.unwrap();
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, router).await });
+        let ready = Client::new()
+            .get(format!("{url}/readiness"))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(ready.status(), StatusCode::OK);
+    }
+}
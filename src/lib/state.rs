@@ -4,20 +4,51 @@ use rquest::ClientBuilder;
 use rquest::Url;
 use rquest::cookie::Cookie;
 use rquest_util::Emulation;
+use tokio::sync::Semaphore;
 use tokio::sync::mpsc::Sender;
 use tokio::sync::oneshot;
+use tokio::sync::watch;
+use tokio::time::Instant;
+use tracing::Level;
+use tracing::Span;
 use tracing::debug;
+use tracing::enabled;
 use tracing::error;
+use tracing::info;
+use tracing::warn;
 
+use std::collections::HashMap;
 use std::str::FromStr;
 use std::sync::Arc;
+use std::sync::Mutex;
 
+use crate::cancel::CancelRegistry;
 use crate::client::AppendHeaders;
 use crate::client::SUPER_CLIENT;
 use crate::config::Config;
 use crate::config::CookieStatus;
 use crate::config::Reason;
+use crate::cookie::CookieOp;
+use crate::cookie::PoolStatus;
 use crate::error::ClewdrError;
+use crate::logging::DUMP_TARGET;
+use crate::utils::print_out_text;
+
+/// API format the client speaks
+#[derive(Clone, Copy, Default, Debug, PartialEq, Eq)]
+pub enum ApiFormat {
+    #[default]
+    Claude,
+    /// Legacy text completion API
+    Legacy,
+    /// OpenAI chat completion API
+    OpenAI,
+}
+
+/// Conversations not deleted yet, by uuid, with their organization and client
+/// Whatever is left at shutdown belongs to dropped requests and is deleted then
+#[derive(Clone, Default)]
+pub struct OpenChats(Arc<Mutex<HashMap<String, (String, Client)>>>);
 
 /// State of current connection
 #[derive(Clone)]
@@ -25,11 +56,32 @@ pub struct AppState {
     pub req_tx: Sender<oneshot::Sender<Result<CookieStatus, ClewdrError>>>,
     pub ret_tx: Sender<(CookieStatus, Option<Reason>)>,
     pub submit_tx: Sender<CookieStatus>,
+    pub admin_tx: Sender<CookieOp>,
     pub cookie: Option<CookieStatus>,
     pub config: Arc<Config>,
     pub org_uuid: Option<String>,
     pub conv_uuid: Option<String>,
     pub client: Client,
+    /// Per-request override of `custom_h`
+    pub custom_h: Option<String>,
+    /// Per-request override of `custom_a`
+    pub custom_a: Option<String>,
+    /// Format of the response sent to the client
+    pub format: ApiFormat,
+    /// Shadow request of shadow mode, sent without padding
+    pub shadow: bool,
+    /// Deadline of the request, from `request_timeout_secs`
+    pub deadline: Option<Instant>,
+    /// Log directory of the request under `log`, None for the shared one
+    pub log_dir: Option<String>,
+    /// In-flight requests, shared by all states
+    pub cancels: CancelRegistry,
+    /// Conversations not deleted yet, shared by all states
+    pub chats: OpenChats,
+    /// Latest counts of the cookie pool
+    pub pool: watch::Receiver<PoolStatus>,
+    /// Bounds concurrent requests to `max_connections`, shared by all states
+    pub limiter: Arc<Semaphore>,
 }
 
 impl AppState {
@@ -39,34 +91,75 @@ impl AppState {
         req_tx: Sender<oneshot::Sender<Result<CookieStatus, ClewdrError>>>,
         ret_tx: Sender<(CookieStatus, Option<Reason>)>,
         submit_tx: Sender<CookieStatus>,
+        admin_tx: Sender<CookieOp>,
+        pool: watch::Receiver<PoolStatus>,
     ) -> Self {
         // Placeholder Client
         let client = SUPER_CLIENT.clone();
+        let limiter = Arc::new(Semaphore::new(config.max_connections));
         AppState {
             config: Arc::new(config),
             req_tx,
             ret_tx,
             submit_tx,
+            admin_tx,
             cookie: None,
             org_uuid: None,
             conv_uuid: None,
             client,
+            custom_h: None,
+            custom_a: None,
+            format: ApiFormat::default(),
+            shadow: false,
+            deadline: None,
+            log_dir: None,
+            cancels: CancelRegistry::default(),
+            chats: OpenChats::default(),
+            pool,
+            limiter,
+        }
+    }
+
+    /// Write json to a log file of the request, unless request logs are disabled
+    /// Also dumped to the log for requests in the debug span
+    pub fn log_json(&self, json: &impl serde::Serialize, file_name: &str) {
+        if self.config.request_log || enabled!(target: DUMP_TARGET, Level::DEBUG) {
+            let text = serde_json::to_string_pretty(json).unwrap_or_default();
+            self.log_text(&text, file_name);
+        }
+    }
+
+    /// Write text to a log file of the request, unless request logs are disabled
+    /// Also dumped to the log for requests in the debug span
+    pub fn log_text(&self, text: &str, file_name: &str) {
+        debug!(target: DUMP_TARGET, "{}:\n{}", file_name, text);
+        if self.config.request_log {
+            print_out_text(self.log_dir.as_deref(), text, file_name);
         }
     }
 
     /// request a new cookie from cookie manager
     pub async fn request_cookie(&mut self) -> Result<(), ClewdrError> {
-        // real client
-        self.client = ClientBuilder::new()
-            .cookie_store(true)
-            .emulation(Emulation::Chrome134)
-            .build()?;
         let (one_tx, one_rx) = oneshot::channel();
         self.req_tx.send(one_tx).await?;
         let res = one_rx.await??;
         self.cookie = Some(res.clone());
+        // real client, with extra headers of the cookie
+        let client = ClientBuilder::new()
+            .cookie_store(true)
+            .emulation(Emulation::Chrome134)
+            .default_headers(res.headers())
+            .build();
+        self.client = match client {
+            Ok(c) => c,
+            Err(e) => {
+                self.return_cookie(None).await;
+                return Err(e.into());
+            }
+        };
         self.store_cookie(res.clone())?;
-        println!("Cookie: {}", res.cookie.to_string().green());
+        Span::current().record("cookie", res.cookie.id());
+        println!("Cookie: {}", res.cookie.id().green());
         Ok(())
     }
 
@@ -74,19 +167,18 @@ impl AppState {
     fn store_cookie(&self, cookie: CookieStatus) -> Result<(), ClewdrError> {
         self.client.set_cookie(
             &Url::from_str(self.config.endpoint().as_str())?,
-            Cookie::parse(cookie.cookie.to_string().as_str())?,
+            Cookie::parse(cookie.cookie.expose().as_str())?,
         );
         Ok(())
     }
 
     /// return the cookie to the cookie manager
     pub async fn return_cookie(&mut self, reason: Option<Reason>) {
-        let c = self
-            .client
-            .get_cookies(&Url::from_str(self.config.endpoint().as_str()).unwrap());
         debug!(
             "Returning cookie: {}",
-            c.map(|c| c.to_str().unwrap().to_string())
+            self.cookie
+                .as_ref()
+                .map(|c| c.cookie.id())
                 .unwrap_or_default()
         );
         // return the cookie to the cookie manager
@@ -100,6 +192,21 @@ impl AppState {
         }
     }
 
+    /// Remember the current conversation until it is deleted
+    pub fn track_chat(&self) {
+        if self.config.preserve_chats {
+            return;
+        }
+        let (Some(org_uuid), Some(conv_uuid)) = (&self.org_uuid, &self.conv_uuid) else {
+            return;
+        };
+        self.chats
+            .0
+            .lock()
+            .unwrap()
+            .insert(conv_uuid.clone(), (org_uuid.clone(), self.client.clone()));
+    }
+
     /// Delete current chat conversation
     pub async fn delete_chat(&self) -> Result<(), ClewdrError> {
         let Some(ref org_uuid) = self.org_uuid else {
@@ -112,6 +219,31 @@ impl AppState {
         if self.config.preserve_chats {
             return Ok(());
         }
+        self.chats.0.lock().unwrap().remove(conv_uuid);
+        self.send_delete(&self.client, org_uuid, conv_uuid).await
+    }
+
+    /// Delete the conversations left by requests dropped before they could do it
+    pub async fn delete_open_chats(&self) {
+        let chats = std::mem::take(&mut *self.chats.0.lock().unwrap());
+        if chats.is_empty() {
+            return;
+        }
+        info!("Deleting {} conversations left open", chats.len());
+        for (conv_uuid, (org_uuid, client)) in chats {
+            if let Err(e) = self.send_delete(&client, &org_uuid, &conv_uuid).await {
+                warn!("Failed to delete chat {}: {}", conv_uuid, e);
+            }
+        }
+    }
+
+    /// Send the request deleting a conversation
+    async fn send_delete(
+        &self,
+        client: &Client,
+        org_uuid: &str,
+        conv_uuid: &str,
+    ) -> Result<(), ClewdrError> {
         debug!("Deleting chat: {}", conv_uuid);
         let endpoint = format!(
             "{}/api/organizations/{}/chat_conversations/{}",
@@ -120,8 +252,7 @@ impl AppState {
             conv_uuid
         );
         let proxy = self.config.rquest_proxy.clone();
-        let _ = self
-            .client
+        let _ = client
             .delete(endpoint)
             .append_headers("", proxy)
             .send()
@@ -129,3 +260,116 @@ impl AppState {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use axum::http::HeaderMap;
+    use serde_json::{Value, json};
+    use tokio::{spawn, sync::Barrier, time::timeout};
+
+    use super::*;
+    use crate::{
+        config::CookieInfo,
+        messages::{ClientRequestBody, handle_messages},
+        testing::{self, Upstream, body_text, cookie, sse},
+    };
+
+    fn request(text: &str) -> ClientRequestBody {
+        serde_json::from_value(json!({
+            "model": "claude",
+            "messages": [{ "role": "user", "content": text }],
+        }))
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn concurrent_requests_do_not_share_conversations() {
+        // completions wait for each other, so both requests are in flight at once
+        let barrier = Arc::new(Barrier::new(2));
+        let upstream = Upstream::start(move |r| {
+            let barrier = barrier.clone();
+            async move {
+                barrier.wait().await;
+                // echo the conversation and the prompt
+                let conv = r.path.rsplit('/').nth(1).unwrap_or_default().to_string();
+                let paste = r.body["attachments"][0]["extracted_content"].clone();
+                sse(&[
+                    format!("{conv} "),
+                    paste.as_str().unwrap_or_default().to_string(),
+                ])
+            }
+        })
+        .await;
+        let config = testing::config(&upstream, &[cookie('a'), cookie('b')]);
+        let state = testing::state(config);
+        let send = |text: &str| {
+            let res = handle_messages(
+                String::new(),
+                state.clone(),
+                HeaderMap::new(),
+                request(text),
+            );
+            spawn(async move { body_text(res.await).await })
+        };
+        let (a, b) = (send("prompt a"), send("prompt b"));
+        let both = timeout(Duration::from_secs(5), async { (a.await, b.await) });
+        let (a, b) = both.await.expect("requests did not overlap");
+        let text = |body: String| {
+            let value: Value = serde_json::from_str(&body).unwrap();
+            value["content"][0]["text"].as_str().unwrap().to_string()
+        };
+        let (a, b) = (text(a.unwrap()), text(b.unwrap()));
+        let (conv_a, prompt_a) = a.split_once(' ').unwrap();
+        let (conv_b, prompt_b) = b.split_once(' ').unwrap();
+        assert_ne!(conv_a, conv_b);
+        assert!(prompt_a.contains("prompt a") && !prompt_a.contains("prompt b"));
+        assert!(prompt_b.contains("prompt b") && !prompt_b.contains("prompt a"));
+        // each request created and deleted its own conversation
+        let received = upstream.received();
+        for conv in [conv_a, conv_b] {
+            assert!(received.iter().any(|r| r.body["uuid"] == conv));
+            assert!(
+                received
+                    .iter()
+                    .any(|r| r.method == "DELETE" && r.path.ends_with(conv))
+            );
+        }
+        assert!(state.conv_uuid.is_none());
+    }
+
+    #[tokio::test]
+    async fn extra_headers_sent_only_with_their_cookie() {
+        let upstream = Upstream::start(|_| async { sse(&["hello"]) }).await;
+        let mut config = testing::config(&upstream, &[cookie('a'), cookie('b')]);
+        config.cookie_array[0].extra_headers = Some(HashMap::from([(
+            "X-Device-Id".to_string(),
+            "device-a".to_string(),
+        )]));
+        let state = testing::state(config);
+        for text in ["one", "two", "three", "four"] {
+            let res = handle_messages(
+                String::new(),
+                state.clone(),
+                HeaderMap::new(),
+                request(text),
+            );
+            body_text(res.await).await;
+        }
+
+        let received = upstream.received();
+        let with = |c: char| {
+            let session = CookieInfo::from(cookie(c).as_str()).expose();
+            received
+                .iter()
+                .filter(move |r| r.headers["cookie"].to_str().unwrap().contains(&session))
+                .collect::<Vec<_>>()
+        };
+        let (a, b) = (with('a'), with('b'));
+        assert!(!a.is_empty() && !b.is_empty());
+        assert_eq!(a.len() + b.len(), received.len());
+        assert!(a.iter().all(|r| r.headers["x-device-id"] == "device-a"));
+        assert!(b.iter().all(|r| !r.headers.contains_key("x-device-id")));
+    }
+}
@@ -0,0 +1,708 @@
+use axum::{
+    body::{Body, Bytes},
+    http::header::{CACHE_CONTROL, CONNECTION, CONTENT_TYPE},
+    response::{IntoResponse, Response},
+};
+use eventsource_stream::Event;
+use futures::{Stream, StreamExt, future::ready, stream};
+use std::{convert::Infallible, fmt::Display, time::Duration};
+use tokio::time::{Instant, timeout_at};
+use tracing::warn;
+
+use crate::{
+    complete::LegacyCompletion,
+    config::StreamMode,
+    error::ClewdrError,
+    openai::{ChatCompletionChunk, Delta, completion_id},
+    state::ApiFormat,
+    text::{
+        PrefixMatch, char_boundary, find_stop, match_prefixes, sanitize_control_chars,
+        stop_prefix_len,
+    },
+    types::message::{
+        ContentBlock, ContentBlockDelta, MessageDeltaContent, MessageStartContent, StopReason,
+        StreamError, StreamEvent,
+    },
+};
+
+/// Abbreviations that do not end a sentence
+const ABBREVIATIONS: [&str; 7] = ["mr", "mrs", "ms", "dr", "st", "vs", "e.g"];
+
+/// Transformer for the SSE stream from Claude Web
+/// Batches text deltas according to the stream mode
+pub struct ClewdrTransformer {
+    mode: StreamMode,
+    buffer: String,
+    index: usize,
+    deadline: Option<Instant>,
+    sanitize: bool,
+    prefixes: Vec<String>,
+    /// Whether the start of the response has been checked for prefixes
+    prefix_checked: bool,
+    /// Held text is sent once it reaches this many bytes, 0 for no limit
+    buffer_size: usize,
+    /// Format of the events sent to the client
+    format: ApiFormat,
+    /// Model name of the request, for legacy and OpenAI events
+    model: String,
+    /// Id of the OpenAI completion
+    id: String,
+    /// Whether the OpenAI role delta has been sent
+    role_sent: bool,
+    /// Cap on the response text in bytes, 0 for no limit
+    max_bytes: usize,
+    /// Bytes of response text received so far
+    received: usize,
+    /// Stop sequences enforced on the response
+    stops: Vec<String>,
+    /// Whether a stop sequence was found
+    stopped: bool,
+    /// Hard deadline, the stream ends with a timeout error
+    hard_deadline: Option<Instant>,
+}
+
+impl ClewdrTransformer {
+    /// Create a new transformer
+    pub fn new(mode: StreamMode) -> Self {
+        Self {
+            mode,
+            buffer: String::new(),
+            index: 0,
+            deadline: None,
+            sanitize: false,
+            prefixes: vec![],
+            prefix_checked: false,
+            buffer_size: 0,
+            format: ApiFormat::Claude,
+            model: String::new(),
+            id: String::new(),
+            role_sent: false,
+            max_bytes: 0,
+            received: 0,
+            stops: vec![],
+            stopped: false,
+            hard_deadline: None,
+        }
+    }
+
+    /// Remove stray control characters from the text
+    pub fn with_sanitize(mut self, sanitize: bool) -> Self {
+        self.sanitize = sanitize;
+        self
+    }
+
+    /// Remove these preambles from the very start of the response
+    pub fn with_prefixes(mut self, prefixes: Vec<String>) -> Self {
+        self.prefixes = prefixes;
+        self
+    }
+
+    /// Send held text once it reaches this many bytes, even without a boundary
+    pub fn with_buffer_size(mut self, size: usize) -> Self {
+        self.buffer_size = size;
+        self
+    }
+
+    /// Emit events in this format for the model
+    pub fn with_format(mut self, format: ApiFormat, model: String) -> Self {
+        self.format = format;
+        self.model = model;
+        if format == ApiFormat::OpenAI {
+            self.id = completion_id();
+        }
+        self
+    }
+
+    /// Finish the stream once the response text exceeds this many bytes
+    pub fn with_max_bytes(mut self, max_bytes: usize) -> Self {
+        self.max_bytes = max_bytes;
+        self
+    }
+
+    /// Cut the response at the first of these sequences and finish the stream
+    pub fn with_stop_sequences(mut self, stops: Vec<String>) -> Self {
+        self.stops = stops;
+        self
+    }
+
+    /// Stop reading upstream after the timeout and finish the stream with partial content
+    pub fn with_soft_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.deadline = timeout.map(|t| Instant::now() + t);
+        self
+    }
+
+    /// End the stream with a timeout error at the deadline
+    pub fn with_deadline(mut self, deadline: Option<Instant>) -> Self {
+        self.hard_deadline = deadline;
+        self
+    }
+
+    /// Earliest deadline, and whether it is the hard one
+    fn next_deadline(&self) -> Option<(Instant, bool)> {
+        match (self.deadline, self.hard_deadline) {
+            (Some(soft), Some(hard)) if soft < hard => Some((soft, false)),
+            (_, Some(hard)) => Some((hard, true)),
+            (soft, None) => soft.map(|s| (s, false)),
+        }
+    }
+
+    /// Whether upstream bytes can be forwarded without transformation
+    pub fn is_passthrough(&self) -> bool {
+        self.mode == StreamMode::Chunk
+            && self.deadline.is_none()
+            && self.hard_deadline.is_none()
+            && !self.sanitize
+            && self.prefixes.is_empty()
+            && self.format == ApiFormat::Claude
+            && self.max_bytes == 0
+            && self.stops.is_empty()
+    }
+
+    /// Whether the response text reached the size cap
+    fn over_limit(&self) -> bool {
+        self.max_bytes > 0 && self.received >= self.max_bytes
+    }
+
+    /// Transform the upstream event stream into SSE bytes for the client
+    pub fn transform_stream<E: Display>(
+        self,
+        input: impl Stream<Item = Result<Event, E>>,
+    ) -> impl Stream<Item = Result<Bytes, Infallible>> {
+        stream::unfold(
+            (self, Box::pin(input), false),
+            |(mut t, mut input, done)| async move {
+                if done {
+                    return None;
+                }
+                let next = match t.next_deadline() {
+                    Some((deadline, hard)) => match timeout_at(deadline, input.next()).await {
+                        Ok(next) => next,
+                        Err(_) if hard => {
+                            warn!("Request timed out, ending the stream");
+                            let out = t.fail("timeout_error", ClewdrError::Timeout.to_string());
+                            return Some((out, (t, input, true)));
+                        }
+                        Err(_) => {
+                            warn!("Soft completion timeout, returning partial content");
+                            let out = t.finish(StopReason::MaxTokens);
+                            return Some((out, (t, input, true)));
+                        }
+                    },
+                    None => input.next().await,
+                };
+                match next {
+                    Some(Ok(event)) => {
+                        let out = t.transform(event);
+                        if t.stopped {
+                            let out = out + t.finish(StopReason::StopSequence).as_str();
+                            return Some((out, (t, input, true)));
+                        }
+                        if t.over_limit() {
+                            warn!("Response exceeds {} bytes, truncating", t.max_bytes);
+                            let out = out + t.finish(StopReason::MaxTokens).as_str();
+                            return Some((out, (t, input, true)));
+                        }
+                        Some((out, (t, input, false)))
+                    }
+                    // end the stream with an error event instead of leaving the client hanging
+                    Some(Err(e)) => {
+                        warn!("Stream Error: {}", e);
+                        Some((t.fail("api_error", e.to_string()), (t, input, true)))
+                    }
+                    // always flush the remaining text at the end of the stream
+                    None => Some((t.flush(), (t, input, true))),
+                }
+            },
+        )
+        .filter(|s| ready(!s.is_empty()))
+        .map(|s| Ok(Bytes::from(s)))
+    }
+
+    /// Forward upstream bytes as they are, see `is_passthrough`
+    /// An upstream error still ends the stream with an error event
+    pub fn passthrough<E: Display>(
+        self,
+        input: impl Stream<Item = Result<Bytes, E>>,
+    ) -> impl Stream<Item = Result<Bytes, Infallible>> {
+        stream::unfold(
+            (self, Box::pin(input), false),
+            |(mut t, mut input, done)| async move {
+                if done {
+                    return None;
+                }
+                match input.next().await? {
+                    Ok(bytes) => Some((bytes, (t, input, false))),
+                    Err(e) => {
+                        warn!("Stream Error: {}", e);
+                        // the blank line ends an event cut off by the error
+                        let out = "\n\n".to_string() + t.fail("api_error", e.to_string()).as_str();
+                        Some((Bytes::from(out), (t, input, true)))
+                    }
+                }
+            },
+        )
+        .map(Ok)
+    }
+
+    /// SSE text of a whole message, for replies that never reach upstream
+    pub fn canned(mut self, text: String) -> String {
+        let start = match self.format {
+            ApiFormat::Claude => [
+                StreamEvent::MessageStart {
+                    message: MessageStartContent::default(),
+                },
+                StreamEvent::ContentBlockStart {
+                    index: 0,
+                    content_block: ContentBlock::Text {
+                        text: String::new(),
+                    },
+                },
+            ]
+            .iter()
+            .map(event_sse)
+            .collect(),
+            _ => String::new(),
+        };
+        start + self.delta(text).as_str() + self.finish(StopReason::EndTurn).as_str()
+    }
+
+    /// Transform a single event, returns the SSE text to send
+    fn transform(&mut self, event: Event) -> String {
+        if let Ok(StreamEvent::ContentBlockDelta {
+            index,
+            delta: ContentBlockDelta::TextDelta { text },
+        }) = serde_json::from_str::<StreamEvent>(&event.data)
+        {
+            let mut text = if self.sanitize {
+                sanitize_control_chars(&text)
+            } else {
+                text
+            };
+            if self.max_bytes > 0 {
+                let remaining = self.max_bytes.saturating_sub(self.received);
+                if text.len() > remaining {
+                    text.truncate(char_boundary(&text, remaining));
+                    // a split char may leave a few bytes unused
+                    self.received = self.max_bytes;
+                } else {
+                    self.received += text.len();
+                }
+            }
+            let mut out = String::new();
+            if index != self.index {
+                out = self.flush();
+                self.index = index;
+            }
+            self.buffer += text.as_str();
+            if !self.strip_prefix() {
+                return out;
+            }
+            if let Some(end) = find_stop(&self.buffer, &self.stops) {
+                self.buffer.truncate(end);
+                self.stopped = true;
+                return out + self.flush().as_str();
+            }
+            // hold back text that may begin a stop sequence
+            let hold = stop_prefix_len(&self.buffer, &self.stops);
+            let held = self.buffer.split_off(self.buffer.len() - hold);
+            let out = out + self.take_complete().as_str();
+            self.buffer += held.as_str();
+            return out;
+        }
+        // flush buffered text before any other event
+        let out = self.flush();
+        match self.format {
+            ApiFormat::Legacy => out + self.legacy_event(&event).as_str(),
+            ApiFormat::OpenAI => out + self.openai_event(&event).as_str(),
+            ApiFormat::Claude => out + sse(&event.event, &event.data).as_str(),
+        }
+    }
+
+    /// Convert a non-text event to a legacy completion event
+    /// Only the stop reason, pings and errors are kept
+    fn legacy_event(&self, event: &Event) -> String {
+        match serde_json::from_str::<StreamEvent>(&event.data) {
+            Ok(StreamEvent::MessageDelta {
+                delta:
+                    MessageDeltaContent {
+                        stop_reason: Some(reason),
+                        ..
+                    },
+                ..
+            }) => self.legacy_sse(String::new(), Some(reason)),
+            Ok(StreamEvent::Ping | StreamEvent::Error { .. }) => sse(&event.event, &event.data),
+            _ => String::new(),
+        }
+    }
+
+    /// Build a legacy completion event
+    fn legacy_sse(&self, text: String, stop_reason: Option<StopReason>) -> String {
+        let completion = LegacyCompletion::new(text, stop_reason, self.model.clone());
+        sse(
+            "completion",
+            &serde_json::to_string(&completion).unwrap_or_default(),
+        )
+    }
+
+    /// Convert a non-text event to OpenAI chunks
+    /// The stream ends with the `[DONE]` sentinel after the message stops
+    fn openai_event(&mut self, event: &Event) -> String {
+        match serde_json::from_str::<StreamEvent>(&event.data) {
+            Ok(StreamEvent::MessageStart { .. }) => self.openai_role(),
+            Ok(StreamEvent::MessageDelta {
+                delta:
+                    MessageDeltaContent {
+                        stop_reason: Some(reason),
+                        ..
+                    },
+                ..
+            }) => self.openai_role() + self.openai_sse(Delta::default(), Some(reason)).as_str(),
+            Ok(StreamEvent::MessageStop) => sse("", "[DONE]"),
+            Ok(StreamEvent::Error { .. }) => sse("", &event.data),
+            _ => String::new(),
+        }
+    }
+
+    /// Role-only delta, clients use the first chunk to initialize the message
+    fn openai_role(&mut self) -> String {
+        if self.role_sent {
+            return String::new();
+        }
+        self.role_sent = true;
+        let delta = Delta {
+            role: Some("assistant"),
+            content: Some(String::new()),
+        };
+        self.openai_sse(delta, None)
+    }
+
+    /// Build an OpenAI chunk event
+    fn openai_sse(&self, delta: Delta, stop_reason: Option<StopReason>) -> String {
+        let chunk = ChatCompletionChunk::new(&self.id, &self.model, delta, stop_reason);
+        sse("", &serde_json::to_string(&chunk).unwrap_or_default())
+    }
+
+    /// Strip a prefix from the start of the response
+    /// Returns false while more text is needed to decide
+    fn strip_prefix(&mut self) -> bool {
+        if self.prefix_checked {
+            return true;
+        }
+        match match_prefixes(&self.buffer, &self.prefixes) {
+            PrefixMatch::Partial => return false,
+            PrefixMatch::Matched(end) => {
+                self.buffer = self.buffer[end..].trim_start().to_string();
+            }
+            PrefixMatch::None => {}
+        }
+        self.prefix_checked = true;
+        true
+    }
+
+    /// Take the complete part of the buffer according to the stream mode
+    fn take_complete(&mut self) -> String {
+        let end = match self.mode {
+            StreamMode::Sentence => sentence_end(&self.buffer),
+            StreamMode::Paragraph => paragraph_end(&self.buffer),
+            StreamMode::Line => self.buffer.rfind('\n').map(|i| i + 1),
+            StreamMode::Token | StreamMode::Chunk => Some(self.buffer.len()),
+        };
+        // whichever comes first, the boundary or the size
+        let full = self.buffer_size > 0 && self.buffer.len() >= self.buffer_size;
+        let end = end.or(full.then_some(self.buffer.len()));
+        let Some(end) = end else {
+            return String::new();
+        };
+        let rest = self.buffer.split_off(end);
+        let text = std::mem::replace(&mut self.buffer, rest);
+        self.delta(text)
+    }
+
+    /// Flush all the buffered text
+    fn flush(&mut self) -> String {
+        // only the very start of the response text is checked
+        self.prefix_checked |= !self.buffer.is_empty();
+        let text = std::mem::take(&mut self.buffer);
+        self.delta(text)
+    }
+
+    /// Finish the stream early with the stop reason
+    fn finish(&mut self, reason: StopReason) -> String {
+        let out = self.flush();
+        match self.format {
+            ApiFormat::Legacy => {
+                return out + self.legacy_sse(String::new(), Some(reason)).as_str();
+            }
+            ApiFormat::OpenAI => {
+                return out
+                    + self.openai_role().as_str()
+                    + self.openai_sse(Delta::default(), Some(reason)).as_str()
+                    + sse("", "[DONE]").as_str();
+            }
+            ApiFormat::Claude => {}
+        }
+        let events = [
+            StreamEvent::ContentBlockStop { index: self.index },
+            StreamEvent::MessageDelta {
+                delta: MessageDeltaContent {
+                    stop_reason: Some(reason),
+                    stop_sequence: None,
+                },
+                usage: None,
+            },
+            StreamEvent::MessageStop,
+        ];
+        events
+            .iter()
+            .fold(out, |out, e| out + event_sse(e).as_str())
+    }
+
+    /// Finish the stream with an error event after the buffered text
+    fn fail(&mut self, type_: &str, message: String) -> String {
+        let out = self.flush();
+        let error = StreamError {
+            type_: type_.to_string(),
+            message,
+        };
+        if self.format == ApiFormat::OpenAI {
+            let data = serde_json::json!({ "error": error });
+            return out + sse("", &data.to_string()).as_str() + sse("", "[DONE]").as_str();
+        }
+        out + event_sse(&StreamEvent::Error { error }).as_str()
+    }
+
+    /// Build a text delta event
+    fn delta(&mut self, text: String) -> String {
+        if text.is_empty() {
+            return String::new();
+        }
+        match self.format {
+            ApiFormat::Legacy => return self.legacy_sse(text, None),
+            ApiFormat::OpenAI => {
+                let delta = Delta {
+                    role: None,
+                    content: Some(text),
+                };
+                return self.openai_role() + self.openai_sse(delta, None).as_str();
+            }
+            ApiFormat::Claude => {}
+        }
+        let event = StreamEvent::ContentBlockDelta {
+            index: self.index,
+            delta: ContentBlockDelta::TextDelta { text },
+        };
+        event_sse(&event)
+    }
+}
+
+/// Build an SSE response with the proper headers
+pub fn sse_response(body: Body) -> Response {
+    (
+        [
+            (CONTENT_TYPE, "text/event-stream"),
+            (CACHE_CONTROL, "no-cache"),
+            (CONNECTION, "keep-alive"),
+        ],
+        body,
+    )
+        .into_response()
+}
+
+/// Format a Claude API event in SSE format, named after its type
+fn event_sse(event: &StreamEvent) -> String {
+    let data = serde_json::to_value(event).unwrap_or_default();
+    let name = data["type"].as_str().unwrap_or_default();
+    sse(name, &data.to_string())
+}
+
+/// Format an event in SSE format
+fn sse(event: &str, data: &str) -> String {
+    if event.is_empty() || event == "message" {
+        format!("data: {data}\n\n")
+    } else {
+        format!("event: {event}\ndata: {data}\n\n")
+    }
+}
+
+/// Find the end of the last complete sentence in the text
+fn sentence_end(text: &str) -> Option<usize> {
+    let mut end = None;
+    let mut chars = text.char_indices().peekable();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '\n' | '。' | '！' | '？' => end = Some(i + c.len_utf8()),
+            '.' | '!' | '?' => {
+                // wait for the next char to decide
+                let Some(&(j, next)) = chars.peek() else {
+                    break;
+                };
+                if next.is_whitespace() && !(c == '.' && is_abbreviation(&text[..i])) {
+                    end = Some(j + next.len_utf8());
+                }
+            }
+            _ => {}
+        }
+    }
+    end
+}
+
+/// Find the end of the last complete paragraph in the text
+fn paragraph_end(text: &str) -> Option<usize> {
+    text.rfind("\n\n").map(|i| i + 2)
+}
+
+/// Check if the text ends with an abbreviation or an initial
+fn is_abbreviation(text: &str) -> bool {
+    let word = text
+        .rsplit(char::is_whitespace)
+        .next()
+        .unwrap_or_default()
+        .to_lowercase();
+    let mut chars = word.chars();
+    let initial = chars.next().is_some_and(char::is_alphabetic) && chars.next().is_none();
+    initial || ABBREVIATIONS.contains(&word.as_str())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Text delta event as sent by upstream
+    fn text_event(text: &str) -> Result<Event, &'static str> {
+        let event = StreamEvent::ContentBlockDelta {
+            index: 0,
+            delta: ContentBlockDelta::TextDelta {
+                text: text.to_string(),
+            },
+        };
+        Ok(Event {
+            event: "content_block_delta".to_string(),
+            data: serde_json::to_string(&event).unwrap(),
+            id: String::new(),
+            retry: None,
+        })
+    }
+
+    /// Texts of the deltas sent to the client, in order
+    async fn deltas(t: ClewdrTransformer, texts: &[&str]) -> Vec<String> {
+        let input = stream::iter(texts.iter().map(|t| text_event(t)).collect::<Vec<_>>());
+        t.transform_stream(input)
+            .map(|b| String::from_utf8(b.unwrap().to_vec()).unwrap())
+            .collect::<String>()
+            .await
+            .split("\n\n")
+            .filter_map(|e| e.split_once("data: "))
+            .filter_map(|(_, data)| match serde_json::from_str(data) {
+                Ok(StreamEvent::ContentBlockDelta {
+                    delta: ContentBlockDelta::TextDelta { text },
+                    ..
+                }) => Some(text),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn sentence_end_finds_last_sentence() {
+        assert_eq!(sentence_end("One. Two. Thr"), Some(10));
+        assert_eq!(sentence_end("Done!\nNext"), Some(6));
+        assert_eq!(sentence_end("好的。然后"), Some(9));
+        assert_eq!(sentence_end("No end yet"), None);
+        // the char after the mark is still unknown
+        assert_eq!(sentence_end("Wait."), None);
+    }
+
+    #[test]
+    fn sentence_end_skips_abbreviations() {
+        assert_eq!(sentence_end("Ask Dr. Smith"), None);
+        assert_eq!(sentence_end("By J. Doe"), None);
+        assert_eq!(sentence_end("See e.g. this"), None);
+        assert_eq!(sentence_end("Hi Mr. Li. Bye"), Some(11));
+    }
+
+    #[tokio::test]
+    async fn sentence_mode_aligns_chunks() {
+        let out = deltas(
+            ClewdrTransformer::new(StreamMode::Sentence),
+            &["Hello wor", "ld. How are", " you? I am", " fine"],
+        )
+        .await;
+        assert_eq!(out, ["Hello world. ", "How are you? ", "I am fine"]);
+    }
+
+    #[tokio::test]
+    async fn openai_role_only_in_first_chunk() {
+        let t = ClewdrTransformer::new(StreamMode::Token)
+            .with_format(ApiFormat::OpenAI, "claude".to_string());
+        let input = stream::iter([text_event("Hel"), text_event("lo")]);
+        let out = t
+            .transform_stream(input)
+            .map(|b| String::from_utf8(b.unwrap().to_vec()).unwrap())
+            .collect::<String>()
+            .await;
+        let deltas = out
+            .split("\n\n")
+            .filter_map(|e| e.strip_prefix("data: "))
+            .filter_map(|d| serde_json::from_str::<serde_json::Value>(d).ok())
+            .map(|v| v["choices"][0]["delta"].clone())
+            .collect::<Vec<_>>();
+        assert_eq!(deltas[0]["role"], "assistant");
+        let contents = deltas
+            .iter()
+            .filter_map(|d| d["content"].as_str())
+            .collect::<String>();
+        assert_eq!(contents, "Hello");
+        assert!(deltas[1..].iter().all(|d| d.get("role").is_none()));
+    }
+
+    #[tokio::test]
+    async fn paragraph_mode_aligns_chunks() {
+        let out = deltas(
+            ClewdrTransformer::new(StreamMode::Paragraph),
+            &["First. Still", " first.\n", "\nSecond", " one.\n\nThird"],
+        )
+        .await;
+        assert_eq!(out, ["First. Still first.\n\n", "Second one.\n\n", "Third"]);
+    }
+
+    #[tokio::test]
+    async fn stream_truncated_at_max_bytes() {
+        let t = ClewdrTransformer::new(StreamMode::Token).with_max_bytes(8);
+        let out = deltas(t, &["four", " more", " never"]).await;
+        assert_eq!(out.concat(), "four mor");
+    }
+
+    #[tokio::test]
+    async fn backspace_removed_from_stream() {
+        let t = ClewdrTransformer::new(StreamMode::Token).with_sanitize(true);
+        let out = deltas(t, &["Hu\x08man", "\x08 text"]).await;
+        assert_eq!(out.concat(), "Human text");
+    }
+
+    #[tokio::test]
+    async fn stop_sequence_split_across_deltas() {
+        let t = ClewdrTransformer::new(StreamMode::Token)
+            .with_stop_sequences(vec!["\n\nHuman:".to_string()]);
+        let out = deltas(t, &["Hi there\n", "\nHum", "an: more"]).await;
+        assert_eq!(out.concat(), "Hi there");
+    }
+
+    #[tokio::test]
+    async fn passthrough_ends_with_error_event() {
+        let input = stream::iter([
+            Ok(Bytes::from("event: ping\ndata: {\"type\": \"ping\"}\n\n")),
+            Err("connection reset"),
+            Ok(Bytes::from("event: ping\n")),
+        ]);
+        let out = ClewdrTransformer::new(StreamMode::Chunk)
+            .passthrough(input)
+            .map(|b| String::from_utf8(b.unwrap().to_vec()).unwrap())
+            .collect::<String>()
+            .await;
+        assert!(out.starts_with("event: ping\n"));
+        assert!(out.ends_with("\n\n"));
+        assert!(out.contains("event: error\n"));
+        assert!(out.contains("connection reset"));
+        assert_eq!(out.matches("event: ping").count(), 1);
+    }
+}
@@ -9,18 +9,23 @@ pub async fn api_submit(
     Auth(_): Auth,
     Json(mut c): Json<CookieStatus>,
 ) -> StatusCode {
+    if s.config.read_only {
+        warn!("Read-only mode, cookie submission rejected");
+        return StatusCode::FORBIDDEN;
+    }
     if !c.cookie.validate() {
-        warn!("Invalid cookie: {}", c.cookie);
+        warn!("Invalid cookie: {}", c.cookie.id());
         return StatusCode::BAD_REQUEST;
     }
     c.reset_time = None;
-    if let Some(t) = c.due {
-        if t < chrono::Utc::now().timestamp() {
-            warn!("Past payment due date: {}", c.cookie);
-            c.due = None;
-        }
+    c.validate_headers();
+    if let Some(t) = c.due
+        && t < chrono::Utc::now().timestamp()
+    {
+        warn!("Past payment due date: {}", c.cookie.id());
+        c.due = None;
     }
-    info!("Cookie accepted: {}", c.cookie);
+    info!("Cookie accepted: {}", c.cookie.id());
     match s.submit_tx.send(c).await {
         Ok(_) => {
             info!("Cookie submitted successfully");
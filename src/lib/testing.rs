@@ -0,0 +1,234 @@
+//! Helpers shared by tests: a mock of the claude.ai endpoints and states wired to it
+
+use std::{
+    future::Future,
+    sync::{Arc, Mutex},
+};
+
+use axum::{
+    Json, Router,
+    body::{Body, Bytes},
+    extract::State,
+    http::{HeaderMap, Method, StatusCode, Uri, header::CONTENT_TYPE},
+    response::{IntoResponse, Response},
+};
+use futures::future::BoxFuture;
+use serde_json::{Value, json};
+use tokio::{
+    net::TcpListener,
+    spawn,
+    sync::{mpsc, watch},
+};
+
+use crate::{
+    config::{Config, CookieStatus},
+    cookie::{CookieManager, PoolStatus},
+    router::RouterBuilder,
+    state::AppState,
+};
+
+/// Request received by the mock upstream
+#[derive(Debug, Clone)]
+pub struct Received {
+    pub method: Method,
+    pub path: String,
+    pub headers: HeaderMap,
+    /// Null if the body is not JSON
+    pub body: Value,
+}
+
+type Reply = Arc<dyn Fn(Received) -> BoxFuture<'static, Response> + Send + Sync>;
+
+#[derive(Clone)]
+struct Mock {
+    received: Arc<Mutex<Vec<Received>>>,
+    reply: Reply,
+}
+
+/// Mock of the claude.ai endpoints, completions are answered by a closure
+pub struct Upstream {
+    pub url: String,
+    received: Arc<Mutex<Vec<Received>>>,
+}
+
+impl Upstream {
+    /// Serve the mock on a free local port
+    pub async fn start<F, Fut>(reply: F) -> Self
+    where
+        F: Fn(Received) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Response> + Send + 'static,
+    {
+        let received = Arc::new(Mutex::new(vec![]));
+        let mock = Mock {
+            received: received.clone(),
+            reply: Arc::new(move |r| Box::pin(reply(r))),
+        };
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let router = Router::new().fallback(handle).with_state(mock);
+        spawn(async move { axum::serve(listener, router).await });
+        Upstream { url, received }
+    }
+
+    /// All requests received so far
+    pub fn received(&self) -> Vec<Received> {
+        self.received.lock().unwrap().clone()
+    }
+}
+
+/// Answer the bootstrap and conversation endpoints, completions go to the closure
+async fn handle(
+    State(mock): State<Mock>,
+    method: Method,
+    uri: Uri,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Response {
+    let received = Received {
+        method: method.clone(),
+        path: uri.path().to_string(),
+        headers,
+        body: serde_json::from_slice(&body).unwrap_or_default(),
+    };
+    mock.received.lock().unwrap().push(received.clone());
+    let path = uri.path();
+    match method {
+        Method::GET if path == "/api/bootstrap" => Json(json!({
+            "account": {
+                "email_address": "test@example.com",
+                "memberships": [{ "organization": {
+                    "name": "test@example.com's Organization",
+                    "capabilities": ["chat", "claude_pro"],
+                } }],
+            }
+        }))
+        .into_response(),
+        Method::GET if path == "/api/organizations" => Json(json!([{
+            "uuid": "org",
+            "capabilities": ["chat", "claude_pro"],
+        }]))
+        .into_response(),
+        Method::POST if path.ends_with("/completion") => (mock.reply)(received).await,
+        Method::POST => (StatusCode::CREATED, Json(json!({}))).into_response(),
+        Method::DELETE => StatusCode::NO_CONTENT.into_response(),
+        _ => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
+/// Upstream SSE body of raw completion events, sent for the `raw` rendering mode
+pub fn sse_body(texts: &[impl AsRef<str>]) -> String {
+    texts
+        .iter()
+        .map(|t| {
+            let data = json!({ "type": "completion", "completion": t.as_ref() });
+            format!("event: completion\ndata: {data}\n\n")
+        })
+        .collect()
+}
+
+/// Upstream SSE body of message events, sent for the `messages` rendering mode
+pub fn messages_body(texts: &[impl AsRef<str>]) -> String {
+    let deltas = texts.iter().map(|t| {
+        json!({
+            "type": "content_block_delta",
+            "index": 0,
+            "delta": { "type": "text_delta", "text": t.as_ref() },
+        })
+    });
+    let events = std::iter::once(json!({
+        "type": "message_start",
+        "message": { "id": "msg", "type": "message", "role": "assistant", "content": [], "model": "claude" },
+    }))
+    .chain(std::iter::once(json!({
+        "type": "content_block_start",
+        "index": 0,
+        "content_block": { "type": "text", "text": "" },
+    })))
+    .chain(deltas)
+    .chain([
+        json!({ "type": "content_block_stop", "index": 0 }),
+        json!({ "type": "message_delta", "delta": { "stop_reason": "end_turn", "stop_sequence": null } }),
+        json!({ "type": "message_stop" }),
+    ]);
+    events
+        .map(|e| format!("event: {}\ndata: {e}\n\n", e["type"].as_str().unwrap()))
+        .collect()
+}
+
+/// Upstream response streaming the texts as raw completion events
+pub fn sse(texts: &[impl AsRef<str>]) -> Response {
+    event_stream(sse_body(texts))
+}
+
+/// Upstream response streaming the texts in the rendering mode of the request
+pub fn reply(received: &Received, texts: &[impl AsRef<str>]) -> Response {
+    if received.body["rendering_mode"] == "messages" {
+        event_stream(messages_body(texts))
+    } else {
+        sse(texts)
+    }
+}
+
+fn event_stream(body: String) -> Response {
+    ([(CONTENT_TYPE, "text/event-stream")], Body::from(body)).into_response()
+}
+
+/// Cookie of valid format made of one repeated character
+pub fn cookie(c: char) -> String {
+    format!(
+        "sk-ant-sid01-{}-{}AA",
+        c.to_string().repeat(86),
+        c.to_string().repeat(6)
+    )
+}
+
+/// Config using the upstream with the cookies, nothing is written to disk
+/// Auth is disabled, so any key is the admin
+pub fn config(upstream: &Upstream, cookies: &[String]) -> Config {
+    let mut config = Config::default();
+    config.rproxy = upstream.url.clone();
+    config.cookie_array = cookies
+        .iter()
+        .map(|c| CookieStatus::new(c, None, None, None))
+        .collect();
+    config.read_only = true;
+    config.request_log = false;
+    config.no_auth = true;
+    config
+}
+
+/// State with a cookie manager running on the config
+pub fn state(config: Config) -> AppState {
+    let (req_tx, req_rx) = mpsc::channel(8);
+    let (ret_tx, ret_rx) = mpsc::channel(8);
+    let (submit_tx, submit_rx) = mpsc::channel(8);
+    let (admin_tx, admin_rx) = mpsc::channel(8);
+    let (status_tx, status_rx) = watch::channel(PoolStatus::default());
+    let manager = CookieManager::new(
+        config.clone(),
+        req_rx,
+        ret_rx,
+        submit_rx,
+        admin_rx,
+        status_tx,
+    );
+    spawn(manager.run());
+    AppState::new(config, req_tx, ret_tx, submit_tx, admin_tx, status_rx)
+}
+
+/// Serve the app router on the state, returns its URL
+pub async fn serve(state: AppState) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    let router = RouterBuilder::new(state).build();
+    spawn(async move { axum::serve(listener, router).await });
+    url
+}
+
+/// Body of a response as text
+pub async fn body_text(res: Response) -> String {
+    let bytes = axum::body::to_bytes(res.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    String::from_utf8_lossy(&bytes).into_owned()
+}
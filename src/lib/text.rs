@@ -1,60 +1,128 @@
 use axum::body::Bytes;
+use claude_tokenizer::count_tokens;
 use eventsource_stream::EventStream;
 use futures::Stream;
 use futures::StreamExt;
 use futures::pin_mut;
-use itertools::Itertools;
-use rand::{Rng, rng};
-use serde_json::Value;
+use rand::{Rng, SeedableRng, rng, rngs::StdRng};
+use serde_json::{Value, json};
 use std::fmt::Write;
-use tracing::error;
+use std::mem;
+use std::sync::Once;
+use std::time::Duration;
+use tokio::time::{Instant, timeout_at};
+use tracing::{debug, error, warn};
 
 use crate::{
+    config::JailbreakPosition,
     messages::{Attachment, ClientRequestBody, RequestBody},
     state::AppState,
-    types::message::{ContentBlock, ImageSource, Message, MessageContent, Role},
-    utils::{TIME_ZONE, print_out_text},
+    types::message::{
+        ContentBlock, ContentBlockDelta, ImageSource, Message, MessageContent, Role, StreamEvent,
+    },
+    utils::{TIME_ZONE, fnv1a},
 };
 
+/// Maximum length of the user message in a utility request
+const UTILITY_MAX_CHARS: usize = 100;
+
+/// Longest slice of tokens taken at once when generating padding
+const PAD_SLICE_MAX: usize = 64;
+
+/// Consecutive blank lines kept when normalizing the prompt
+const MAX_BLANK_LINES: usize = 2;
+
+/// Warn only once when padding falls back to the custom prompt
+static PAD_FALLBACK: Once = Once::new();
+
 /// Merged messages and images
 #[derive(Default, Debug)]
 pub struct Merged {
     pub paste: String,
     pub prompt: String,
     pub images: Vec<ImageSource>,
+    pub padding: Option<(String, usize)>,
 }
 
 impl AppState {
     /// Transform the request body from Claude API to Claude web
     pub fn transform(&self, value: ClientRequestBody) -> Option<RequestBody> {
         let system = merge_system(value.system);
-        let merged = self.merge_messages(value.messages, system)?;
+        // utility requests skip the prompt polyfill
+        let utility = self.config.utility_fast_path && is_utility(&value.messages, &system);
+        if utility {
+            debug!("Utility request, skipping padding and custom prompt");
+        }
+        let pad = !utility && !self.shadow && (!value.stream || self.config.padtxt_stream);
+        let merged = self.merge_messages(value.messages, system, pad, !utility)?;
+        let (attachments, prompt) = if self.config.inline_prompt(&value.model) {
+            debug!("Sending prompt inline for model {}", value.model);
+            let prompt = [merged.paste, merged.prompt]
+                .into_iter()
+                .filter(|s| !s.is_empty())
+                .collect::<Vec<_>>()
+                .join("\n\n");
+            (vec![], prompt)
+        } else {
+            (
+                vec![Attachment::new(
+                    merged.paste,
+                    &self.config.attachment_file_name,
+                    &self.config.attachment_file_type,
+                )],
+                merged.prompt,
+            )
+        };
+        // upstream rejects a zero limit
+        let max_tokens = match value.max_tokens {
+            Some(0) => {
+                warn!(
+                    "max_tokens of 0 is invalid, using {}",
+                    self.config.default_max_tokens
+                );
+                self.config.default_max_tokens
+            }
+            t => t.unwrap_or(self.config.default_max_tokens),
+        };
         Some(RequestBody {
-            max_tokens_to_sample: value.max_tokens,
-            attachments: vec![Attachment::new(merged.paste)],
+            max_tokens_to_sample: max_tokens,
+            attachments,
             files: vec![],
             model: value.model,
-            rendering_mode: if value.stream {
-                "messages".to_string()
-            } else {
-                "raw".to_string()
-            },
-            prompt: merged.prompt,
+            rendering_mode: self.config.rendering_mode.as_str(value.stream).to_string(),
+            prompt,
             timezone: TIME_ZONE.to_string(),
+            stop_sequences: value
+                .stop_sequences
+                .into_iter()
+                .filter(|s| !s.is_empty())
+                .collect(),
             images: merged.images,
+            padding: merged.padding,
         })
     }
 
     /// Merge messages into strings and extract images
-    fn merge_messages(&self, msgs: Vec<Message>, system: String) -> Option<Merged> {
+    fn merge_messages(
+        &self,
+        msgs: Vec<Message>,
+        system: String,
+        pad: bool,
+        custom_prompt: bool,
+    ) -> Option<Merged> {
         if msgs.is_empty() {
             return None;
         }
-        let h = self.config.custom_h.clone().unwrap_or("Human".to_string());
+        // request headers take precedence over config
+        let h = self
+            .custom_h
+            .clone()
+            .or(self.config.custom_h.clone())
+            .unwrap_or("Human".to_string());
         let a = self
-            .config
             .custom_a
             .clone()
+            .or(self.config.custom_a.clone())
             .unwrap_or("Assistant".to_string());
 
         let user_real_roles = self.config.use_real_roles;
@@ -64,85 +132,151 @@ impl AppState {
         // preallocate string to avoid reallocations
         let mut w = String::with_capacity(size);
         // generate padding text
-        if !self.config.pad_tokens.is_empty() {
-            let len = self.config.padtxt_len;
-            let padding = self.generate_padding(len);
-            w.push_str(padding.as_str());
+        let len = self.config.padtxt_len;
+        let pad_ready = self.config.pad_tokens.len() >= len.max(PAD_SLICE_MAX);
+        let mut padding = None;
+        if pad && pad_ready {
+            let (text, tokens) = self.generate_padding(len);
+            w.push_str(text.as_str());
+            padding = Some((text, tokens));
+        } else if pad && !self.config.padtxt_file.trim().is_empty() {
+            // custom prompt below is still added, so the request goes on without padding
+            PAD_FALLBACK.call_once(|| {
+                warn!("Padding tokens unavailable, falling back to custom prompt");
+            });
         }
 
         let mut imgs: Vec<ImageSource> = vec![];
 
-        let chunks = msgs
+        let msgs = reorder_messages(msgs, self.config.jailbreak_position)
             .into_iter()
-            .map_while(|m| match m.content {
-                MessageContent::Blocks { content } => {
-                    // collect all text blocks, join them with new line
-                    let blocks = content
-                        .into_iter()
-                        .map_while(|b| match b {
-                            ContentBlock::Text { text } => Some(text.trim().to_string()),
-                            ContentBlock::Image { source } => {
-                                // push image to the list
-                                imgs.push(source);
-                                None
-                            }
-                            _ => None,
-                        })
-                        .collect::<Vec<_>>()
-                        .join("\n");
-                    if blocks.is_empty() {
-                        None
-                    } else {
-                        Some((m.role, blocks))
+            // drop discarded messages
+            .filter(|m| !m.discard.unwrap_or_default())
+            // messages left without text are skipped, the later ones are kept
+            .filter_map(|m| {
+                let text = match m.content {
+                    MessageContent::Blocks { content } => {
+                        // collect all text blocks, join them with new line
+                        content
+                            .into_iter()
+                            .filter_map(|b| match b {
+                                ContentBlock::Text { text } => Some(text.trim().to_string()),
+                                ContentBlock::Image { source } => {
+                                    // push image to the list
+                                    imgs.push(source);
+                                    None
+                                }
+                                // tools are not available on the web, keep them as text
+                                ContentBlock::ToolUse { name, input, .. } => {
+                                    Some(format!("[Tool call: {}({})]", name, input))
+                                }
+                                ContentBlock::ToolResult { content, .. } => {
+                                    Some(format!("[Tool result: {}]", tool_result_text(content)))
+                                }
+                            })
+                            .collect::<Vec<_>>()
+                            .join("\n")
                     }
-                }
-                MessageContent::Text { content } => {
                     // plain text
-                    let content = content.trim().to_string();
-                    if content.is_empty() {
-                        None
-                    } else {
-                        Some((m.role, content))
-                    }
+                    MessageContent::Text { content } => content.trim().to_string(),
+                };
+                let text = if m.strip.unwrap_or_default() {
+                    strip_text(&text)
+                } else {
+                    text
+                };
+                if text.is_empty() {
+                    None
+                } else {
+                    Some((m.role, text, m.merged.unwrap_or_default()))
                 }
-            })
-            // chunk by role
-            .chunk_by(|m| m.0.clone());
-        // join same role with new line
-        let mut msgs = chunks.into_iter().map(|(role, grp)| {
-            let txt = grp.into_iter().map(|m| m.1).collect::<Vec<_>>().join("\n");
-            (role, txt)
-        });
+            });
+        // join same role or merged messages with new line
+        // merged system messages are folded into the next user turn instead
+        let separator = self.config.merged_system_separator.as_str();
+        let mut chunks: Vec<(Role, String)> = vec![];
+        let mut pending: Vec<String> = vec![];
+        for (role, text, merged) in msgs {
+            if role == Role::System && merged {
+                pending.push(text);
+                continue;
+            }
+            let text = if role == Role::User && !pending.is_empty() {
+                pending.push(text);
+                mem::take(&mut pending).join(separator)
+            } else {
+                text
+            };
+            match chunks.last_mut() {
+                Some((last, txt)) if *last == role || (merged && role != Role::System) => {
+                    txt.push('\n');
+                    txt.push_str(text.as_str());
+                }
+                _ => chunks.push((role, text)),
+            }
+        }
+        // no user turn followed, send them as plain system text
+        if !pending.is_empty() {
+            chunks.push((Role::System, pending.join(separator)));
+        }
+        // keep recent turns inline in the prompt, older history stays in the attachment
+        let threshold = self.config.history_as_attachment_threshold;
+        let inline_from = if threshold > 0 {
+            chunks.len().saturating_sub(threshold)
+        } else {
+            chunks.len()
+        };
+        let mut inline = String::new();
+        let mut msgs = chunks.into_iter().enumerate();
         // first message does not need prefix
         if !system.is_empty() {
             w += system.as_str();
         } else {
-            let first = msgs.next()?;
+            let (_, first) = msgs.next()?;
             w += first.1.as_str();
         }
-        for (role, text) in msgs {
+        for (i, (role, text)) in msgs {
             let prefix = match role {
                 Role::User => format!("{}: ", h),
                 Role::Assistant => format!("{}: ", a),
+                Role::System => String::new(),
+            };
+            let target = if i >= inline_from {
+                &mut inline
+            } else {
+                &mut w
             };
-            write!(w, "{}{}{}", line_breaks, prefix, text).unwrap();
+            write!(target, "{}{}{}", line_breaks, prefix, text).unwrap();
+        }
+        let mut w = self.config.replace_prompt(w);
+        let mut inline = self.config.replace_prompt(inline);
+        if self.config.trim_prompt {
+            w = normalize_prompt(&w);
+            inline = normalize_prompt(&inline);
         }
-        print_out_text(w.as_str(), "paste.txt");
 
         // prompt polyfill
-        let p = self.config.custom_prompt.clone();
+        let mut p = inline.trim_start().to_string();
+        if custom_prompt && !self.config.custom_prompt.is_empty() {
+            if !p.is_empty() {
+                p += "\n\n";
+            }
+            p += self.config.custom_prompt.as_str();
+        }
 
         Some(Merged {
             paste: w,
             prompt: p,
             images: imgs,
+            padding,
         })
     }
 
-    /// Generate padding text
-    fn generate_padding(&self, length: usize) -> String {
+    /// Generate padding text and the number of tokens in it
+    /// Callers make sure there are enough pad tokens
+    fn generate_padding(&self, length: usize) -> (String, usize) {
         if length == 0 {
-            return String::new();
+            return (String::new(), 0);
         }
         let conf = &self.config;
         let tokens = conf
@@ -150,13 +284,15 @@ impl AppState {
             .iter()
             .map(|s| s.as_str())
             .collect::<Vec<_>>();
-        assert!(tokens.len() >= length, "Padding tokens too short");
 
         let mut result = String::with_capacity(length * 8);
-        let mut rng = rng();
+        let mut rng = match conf.padtxt_seed {
+            Some(seed) => StdRng::seed_from_u64(seed),
+            None => StdRng::from_rng(&mut rng()),
+        };
         let mut pushed = 0;
         loop {
-            let slice_len = rng.random_range(16..64);
+            let slice_len = rng.random_range(16..PAD_SLICE_MAX);
             let slice_start = rng.random_range(0..tokens.len() - slice_len);
             let slice = &tokens[slice_start..slice_start + slice_len];
             result.push_str(slice.join(" ").as_str());
@@ -169,10 +305,226 @@ impl AppState {
                 break;
             }
         }
-        print_out_text(result.as_str(), "padding.txt");
+        debug!("Padding tokens: {}", pushed);
         result.push_str("\n\n");
-        result
+        (result, pushed)
+    }
+}
+
+/// Short hash of the normalized prompt, used to name conversations
+/// Identical prompts get the same hash regardless of whitespace
+pub fn prompt_hash(value: &ClientRequestBody) -> String {
+    let system = merge_system(value.system.clone());
+    let texts = value.messages.iter().map(|m| match m.content {
+        MessageContent::Text { ref content } => content.clone(),
+        MessageContent::Blocks { ref content } => content
+            .iter()
+            .filter_map(|b| match b {
+                ContentBlock::Text { text } => Some(text.as_str()),
+                _ => None,
+            })
+            .collect::<Vec<_>>()
+            .join("\n"),
+    });
+    let prompt = std::iter::once(system)
+        .chain(texts)
+        .collect::<Vec<_>>()
+        .join("\n");
+    let hash = fnv1a(
+        prompt
+            .split_whitespace()
+            .flat_map(|w| w.bytes().chain(std::iter::once(b' '))),
+    );
+    format!("{:08x}", (hash >> 32) as u32)
+}
+
+/// Check if the request is a system instruction with a single short user message
+fn is_utility(msgs: &[Message], system: &str) -> bool {
+    let [msg] = msgs else {
+        return false;
+    };
+    if system.trim().is_empty() || msg.role != Role::User {
+        return false;
     }
+    let len = match msg.content {
+        MessageContent::Text { ref content } => content.trim().chars().count(),
+        MessageContent::Blocks { ref content } => content
+            .iter()
+            .map(|b| match b {
+                ContentBlock::Text { text } => text.trim().chars().count(),
+                _ => UTILITY_MAX_CHARS,
+            })
+            .sum(),
+    };
+    len < UTILITY_MAX_CHARS
+}
+
+/// Move `main` messages to the front and `jailbreak` messages to the configured position
+fn reorder_messages(msgs: Vec<Message>, position: JailbreakPosition) -> Vec<Message> {
+    let (main, rest): (Vec<_>, Vec<_>) = msgs.into_iter().partition(|m| m.main.unwrap_or_default());
+    let (jailbreak, mut rest): (Vec<_>, Vec<_>) = if position == JailbreakPosition::InPlace {
+        (vec![], rest)
+    } else {
+        rest.into_iter()
+            .partition(|m| m.jailbreak.unwrap_or_default())
+    };
+    let at = match position {
+        JailbreakPosition::InPlace | JailbreakPosition::Last => rest.len(),
+        JailbreakPosition::BeforeLastUser => rest
+            .iter()
+            .rposition(|m| m.role == Role::User)
+            .unwrap_or(rest.len()),
+    };
+    rest.splice(at..at, jailbreak);
+    main.into_iter().chain(rest).collect()
+}
+
+/// Remove control characters such as `\x08` while keeping whitespace
+pub fn sanitize_control_chars(text: &str) -> String {
+    text.chars()
+        .filter(|c| !c.is_control() || c.is_whitespace())
+        .collect()
+}
+
+/// Result of matching the start of a response against the prefixes
+pub enum PrefixMatch {
+    /// A prefix matched, ending at this byte offset
+    Matched(usize),
+    /// The text is too short to decide yet
+    Partial,
+    /// No prefix can match
+    None,
+}
+
+/// Match the start of the text against the prefixes, case-insensitive
+pub fn match_prefixes(text: &str, prefixes: &[String]) -> PrefixMatch {
+    let mut partial = false;
+    for prefix in prefixes.iter().filter(|p| !p.is_empty()) {
+        let mut chars = text.char_indices();
+        let mut end = Some(0);
+        for p in prefix.chars() {
+            match chars.next() {
+                Some((i, c)) if c.to_lowercase().eq(p.to_lowercase()) => {
+                    end = Some(i + c.len_utf8())
+                }
+                Some(_) => {
+                    end = None;
+                    break;
+                }
+                None => {
+                    partial = true;
+                    end = None;
+                    break;
+                }
+            }
+        }
+        if let Some(end) = end {
+            return PrefixMatch::Matched(end);
+        }
+    }
+    if partial {
+        PrefixMatch::Partial
+    } else {
+        PrefixMatch::None
+    }
+}
+
+/// Remove the first matching prefix from the start of the response
+pub fn strip_response_prefix<'a>(text: &'a str, prefixes: &[String]) -> &'a str {
+    match match_prefixes(text, prefixes) {
+        PrefixMatch::Matched(end) => text[end..].trim_start(),
+        _ => text,
+    }
+}
+
+/// Render the response template into a JSON value
+/// `{{content}}`, `{{model}}`, `{{finish_reason}}` and `{{usage}}` are replaced with
+/// JSON values, so they must not be quoted in the template
+pub fn render_template(
+    template: &str,
+    content: &str,
+    model: &str,
+    finish_reason: &str,
+) -> Result<Value, serde_json::Error> {
+    let usage = json!({
+        "output_tokens": count_tokens(content).unwrap_or_default(),
+    });
+    let rendered = template
+        .replace("{{content}}", &json!(content).to_string())
+        .replace("{{model}}", &json!(model).to_string())
+        .replace("{{finish_reason}}", &json!(finish_reason).to_string())
+        .replace("{{usage}}", &usage.to_string());
+    serde_json::from_str(&rendered)
+}
+
+/// Trim the prompt and collapse runs of blank lines longer than `MAX_BLANK_LINES`
+/// Whitespace within lines is kept as is
+fn normalize_prompt(text: &str) -> String {
+    let mut blank = 0;
+    text.trim()
+        .lines()
+        .filter(|l| {
+            if l.trim().is_empty() {
+                blank += 1;
+                blank <= MAX_BLANK_LINES
+            } else {
+                blank = 0;
+                true
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Position of the earliest stop sequence in the text
+pub fn find_stop(text: &str, stops: &[String]) -> Option<usize> {
+    stops.iter().filter_map(|s| text.find(s.as_str())).min()
+}
+
+/// Length of the longest tail of the text that may begin a stop sequence
+/// This part must be held back until more text arrives
+pub fn stop_prefix_len(text: &str, stops: &[String]) -> usize {
+    stops
+        .iter()
+        .flat_map(|s| s.char_indices().skip(1).map(|(i, _)| &s[..i]))
+        .filter(|p| text.ends_with(p))
+        .map(str::len)
+        .max()
+        .unwrap_or_default()
+}
+
+/// Largest char boundary of the text not after `index`
+pub fn char_boundary(text: &str, index: usize) -> usize {
+    if index >= text.len() {
+        return text.len();
+    }
+    (0..=index)
+        .rev()
+        .find(|i| text.is_char_boundary(*i))
+        .unwrap_or_default()
+}
+
+/// Text of a tool result, its content is a string or a list of blocks
+fn tool_result_text(content: Value) -> String {
+    match content {
+        Value::String(text) => text.trim().to_string(),
+        Value::Array(blocks) => blocks
+            .iter()
+            .filter_map(|b| b["text"].as_str())
+            .map(str::trim)
+            .collect::<Vec<_>>()
+            .join("\n"),
+        other => other.to_string(),
+    }
+}
+
+/// Trim every line and drop blank lines
+fn strip_text(text: &str) -> String {
+    text.lines()
+        .map(str::trim)
+        .filter(|l| !l.is_empty())
+        .collect::<Vec<_>>()
+        .join("\n")
 }
 
 /// Merge system message into a string
@@ -183,38 +535,649 @@ fn merge_system(sys: Value) -> String {
     let Some(arr) = sys.as_array() else {
         return String::new();
     };
+    // skip non-text blocks, `cache_control` and other keys are ignored
     arr.iter()
-        .map_while(|v| v["text"].as_str())
+        .filter_map(|v| v["text"].as_str())
         .map(|v| v.trim())
-        .to_owned()
         .collect::<Vec<_>>()
         .join("\n")
 }
 
+/// Merge the text of a response into a string
+/// Both `raw` completion events and `messages` text deltas are accepted
+/// Stop reading and return partial content when the soft timeout is reached
+/// or the text exceeds `max_bytes`, 0 for no limit
+/// The flag tells whether the text was cut short by one of them
 pub async fn merge_sse(
     stream: EventStream<impl Stream<Item = Result<Bytes, rquest::Error>>>,
-) -> String {
+    soft_timeout: Option<Duration>,
+    max_bytes: usize,
+) -> (String, bool) {
     pin_mut!(stream);
     let mut w = String::new();
-    while let Some(event) = stream.next().await {
+    let deadline = soft_timeout.map(|t| Instant::now() + t);
+    loop {
+        let next = match deadline {
+            Some(deadline) => match timeout_at(deadline, stream.next()).await {
+                Ok(next) => next,
+                Err(_) => {
+                    warn!("Soft completion timeout, returning partial content");
+                    return (w, true);
+                }
+            },
+            None => stream.next().await,
+        };
+        let Some(event) = next else {
+            break;
+        };
         match event {
             Ok(event) => {
-                if event.event != "completion" {
-                    continue;
-                }
                 let data = event.data;
-                let Ok(json) = serde_json::from_str::<Value>(&data) else {
-                    error!("Failed to parse JSON: {}", data);
-                    continue;
-                };
-                let Some(completion) = json["completion"].as_str() else {
-                    error!("Failed to get completion from JSON: {}", json);
-                    continue;
-                };
-                w += completion;
+                match event.event.as_str() {
+                    "completion" => {
+                        let Ok(json) = serde_json::from_str::<Value>(&data) else {
+                            error!("Failed to parse JSON: {}", data);
+                            continue;
+                        };
+                        let Some(completion) = json["completion"].as_str() else {
+                            error!("Failed to get completion from JSON: {}", json);
+                            continue;
+                        };
+                        w += completion;
+                    }
+                    "content_block_delta" => {
+                        let Ok(StreamEvent::ContentBlockDelta {
+                            delta: ContentBlockDelta::TextDelta { text },
+                            ..
+                        }) = serde_json::from_str::<StreamEvent>(&data)
+                        else {
+                            continue;
+                        };
+                        w += &text;
+                    }
+                    _ => continue,
+                }
+                if max_bytes > 0 && w.len() > max_bytes {
+                    warn!("Response exceeds {} bytes, truncating", max_bytes);
+                    w.truncate(char_boundary(&w, max_bytes));
+                    return (w, true);
+                }
             }
             Err(e) => error!("Stream Error: {}", e),
         }
     }
-    w
+    (w, false)
+}
+
+#[cfg(test)]
+mod tests {
+    use eventsource_stream::Eventsource;
+
+    use super::*;
+    use crate::config::{Config, RenderingMode};
+
+    fn strings(values: &[&str]) -> Vec<String> {
+        values.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn prefix_matched_at_start_only() {
+        let prefixes = strings(&["Sure, here is", "Certainly!"]);
+        assert!(matches!(
+            match_prefixes("sure, here is the story", &prefixes),
+            PrefixMatch::Matched(13)
+        ));
+        assert!(matches!(
+            match_prefixes("Sure, he", &prefixes),
+            PrefixMatch::Partial
+        ));
+        assert!(matches!(
+            match_prefixes("I said: Certainly!", &prefixes),
+            PrefixMatch::None
+        ));
+    }
+
+    #[test]
+    fn prefix_stripped_from_response() {
+        let prefixes = strings(&["Certainly!"]);
+        assert_eq!(
+            strip_response_prefix("Certainly!  Here you go", &prefixes),
+            "Here you go"
+        );
+        assert_eq!(
+            strip_response_prefix("Here you go. Certainly!", &prefixes),
+            "Here you go. Certainly!"
+        );
+        assert_eq!(strip_response_prefix("Cert", &prefixes), "Cert");
+    }
+
+    #[test]
+    fn earliest_stop_found() {
+        let stops = strings(&["\n\nHuman:", "END"]);
+        assert_eq!(find_stop("abc END x\n\nHuman:", &stops), Some(4));
+        assert_eq!(find_stop("abc", &stops), None);
+        assert_eq!(find_stop("abc", &[]), None);
+    }
+
+    #[test]
+    fn stop_prefix_held_back() {
+        let stops = strings(&["\n\nHuman:", "END"]);
+        assert_eq!(stop_prefix_len("text\n\nHum", &stops), 5);
+        assert_eq!(stop_prefix_len("THE EN", &stops), 2);
+        assert_eq!(stop_prefix_len("THAT", &stops), 0);
+        // a whole stop sequence is for find_stop
+        assert_eq!(stop_prefix_len("END", &stops), 0);
+        assert_eq!(stop_prefix_len("a", &[]), 0);
+    }
+
+    /// Text of each message, in order
+    fn texts(msgs: &[Message]) -> Vec<&str> {
+        msgs.iter()
+            .map(|m| match m.content {
+                MessageContent::Text { ref content } => content.as_str(),
+                MessageContent::Blocks { .. } => "",
+            })
+            .collect()
+    }
+
+    fn flagged(role: Role, text: &str, main: bool, jailbreak: bool) -> Message {
+        Message {
+            main: main.then_some(true),
+            jailbreak: jailbreak.then_some(true),
+            ..Message::new_text(role, text)
+        }
+    }
+
+    fn conversation() -> Vec<Message> {
+        vec![
+            Message::new_text(Role::User, "u1"),
+            flagged(Role::System, "jb", false, true),
+            Message::new_text(Role::Assistant, "a1"),
+            Message::new_text(Role::User, "u2"),
+            flagged(Role::System, "main", true, false),
+            Message::new_text(Role::Assistant, "a2"),
+        ]
+    }
+
+    #[test]
+    fn jailbreak_in_place() {
+        let msgs = reorder_messages(conversation(), JailbreakPosition::InPlace);
+        assert_eq!(texts(&msgs), ["main", "u1", "jb", "a1", "u2", "a2"]);
+    }
+
+    #[test]
+    fn jailbreak_last() {
+        let msgs = reorder_messages(conversation(), JailbreakPosition::Last);
+        assert_eq!(texts(&msgs), ["main", "u1", "a1", "u2", "a2", "jb"]);
+    }
+
+    #[test]
+    fn jailbreak_before_last_user() {
+        let msgs = reorder_messages(conversation(), JailbreakPosition::BeforeLastUser);
+        assert_eq!(texts(&msgs), ["main", "u1", "a1", "jb", "u2", "a2"]);
+    }
+
+    #[test]
+    fn blank_lines_collapsed() {
+        let prompt = "\n\n  Human: hi\n\n\n\n\nAssistant: yo  \n\n";
+        assert_eq!(normalize_prompt(prompt), "Human: hi\n\n\nAssistant: yo");
+    }
+
+    #[test]
+    fn internal_spacing_kept() {
+        let prompt = "def f():\n    return  1\n\n\nx = f()";
+        assert_eq!(normalize_prompt(prompt), prompt);
+    }
+
+    #[test]
+    fn template_rendered_as_json() {
+        let template = r#"{"text": {{content}}, "model": {{model}}, "stop": {{finish_reason}}, "usage": {{usage}}}"#;
+        let value = render_template(template, "say \"hi\"\n", "claude", "end_turn").unwrap();
+        assert_eq!(value["text"], "say \"hi\"\n");
+        assert_eq!(value["model"], "claude");
+        assert_eq!(value["stop"], "end_turn");
+        assert!(value["usage"]["output_tokens"].as_u64().unwrap() > 0);
+    }
+
+    #[test]
+    fn quoted_placeholder_is_invalid() {
+        assert!(render_template(r#"{"text": "{{content}}"}"#, "hi", "m", "end_turn").is_err());
+    }
+
+    fn request(system: &str, user: &str) -> ClientRequestBody {
+        serde_json::from_value(json!({
+            "model": "claude",
+            "system": system,
+            "messages": [{ "role": "user", "content": user }],
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn identical_prompts_share_hash() {
+        let a = prompt_hash(&request("Be nice.", "Hello  there"));
+        let b = prompt_hash(&request("Be nice.", "Hello\nthere "));
+        assert_eq!(a, b);
+        assert_eq!(a.len(), 8);
+    }
+
+    #[test]
+    fn different_prompts_differ() {
+        let a = prompt_hash(&request("Be nice.", "Hello there"));
+        assert_ne!(a, prompt_hash(&request("Be nice.", "Goodbye there")));
+        assert_ne!(a, prompt_hash(&request("Be mean.", "Hello there")));
+    }
+
+    #[test]
+    fn control_chars_removed() {
+        assert_eq!(sanitize_control_chars("a\x08b\x00c"), "abc");
+        assert_eq!(
+            sanitize_control_chars("line\n\tnext\r\n"),
+            "line\n\tnext\r\n"
+        );
+    }
+
+    /// State of a request with the config, the channels are never used
+    fn state(config: Config) -> AppState {
+        let (req_tx, _) = tokio::sync::mpsc::channel(1);
+        let (ret_tx, _) = tokio::sync::mpsc::channel(1);
+        let (submit_tx, _) = tokio::sync::mpsc::channel(1);
+        let (admin_tx, _) = tokio::sync::mpsc::channel(1);
+        let (_, pool) = tokio::sync::watch::channel(Default::default());
+        AppState::new(config, req_tx, ret_tx, submit_tx, admin_tx, pool)
+    }
+
+    fn merged(msgs: Vec<Message>, system: &str) -> String {
+        state(Config::default())
+            .merge_messages(msgs, system.to_string(), false, false)
+            .unwrap()
+            .paste
+    }
+
+    #[test]
+    fn discarded_message_dropped() {
+        let msgs = vec![
+            Message::new_text(Role::User, "hi"),
+            Message {
+                discard: Some(true),
+                ..Message::new_text(Role::Assistant, "gone")
+            },
+            Message::new_text(Role::Assistant, "hello"),
+        ];
+        assert_eq!(merged(msgs, "sys"), "sys\n\nHuman: hi\n\nAssistant: hello");
+    }
+
+    #[test]
+    fn stripped_message_trimmed() {
+        let msgs = vec![Message {
+            strip: Some(true),
+            ..Message::new_text(Role::User, "  a  \n\n   \n b ")
+        }];
+        assert_eq!(merged(msgs, "sys"), "sys\n\nHuman: a\nb");
+    }
+
+    #[test]
+    fn tool_blocks_round_trip_into_paste() {
+        let raw = json!([
+            { "role": "user", "content": "Weather in Paris?" },
+            { "role": "assistant", "content": [
+                { "type": "text", "text": "Checking." },
+                { "type": "tool_use", "id": "call_1", "name": "get_weather", "input": { "city": "Paris" } },
+            ] },
+            { "role": "user", "content": [
+                { "type": "tool_result", "tool_use_id": "call_1", "content": "18C, sunny" },
+            ] },
+            { "role": "user", "content": [
+                { "type": "tool_result", "tool_use_id": "call_1", "content": [
+                    { "type": "text", "text": "humidity 40%" },
+                ] },
+            ] },
+        ]);
+        let msgs: Vec<Message> = serde_json::from_value(raw.clone()).unwrap();
+        assert_eq!(serde_json::to_value(&msgs).unwrap(), raw);
+        assert_eq!(
+            merged(msgs, "sys"),
+            "sys\n\nHuman: Weather in Paris?\n\n\
+             Assistant: Checking.\n[Tool call: get_weather({\"city\":\"Paris\"})]\n\n\
+             Human: [Tool result: 18C, sunny]\n[Tool result: humidity 40%]"
+        );
+    }
+
+    #[test]
+    fn merged_message_joins_previous() {
+        let msgs = vec![
+            Message::new_text(Role::User, "hi"),
+            Message {
+                merged: Some(true),
+                ..Message::new_text(Role::Assistant, "still user")
+            },
+            Message::new_text(Role::Assistant, "hello"),
+        ];
+        assert_eq!(
+            merged(msgs, "sys"),
+            "sys\n\nHuman: hi\nstill user\n\nAssistant: hello"
+        );
+    }
+
+    #[test]
+    fn merged_system_folded_into_next_user() {
+        let msgs = vec![
+            Message::new_text(Role::User, "hi"),
+            Message::new_text(Role::Assistant, "hello"),
+            Message {
+                merged: Some(true),
+                ..Message::new_text(Role::System, "note")
+            },
+            Message::new_text(Role::User, "bye"),
+        ];
+        assert_eq!(
+            merged(msgs, "sys"),
+            "sys\n\nHuman: hi\n\nAssistant: hello\n\nHuman: note\n\nbye"
+        );
+    }
+
+    /// Upstream body of SSE events, optionally never ending
+    fn upstream(
+        events: &[(&str, Value)],
+        hang: bool,
+    ) -> EventStream<impl Stream<Item = Result<Bytes, rquest::Error>> + use<>> {
+        let chunks = events
+            .iter()
+            .map(|(event, data)| Ok(Bytes::from(format!("event: {event}\ndata: {data}\n\n"))))
+            .collect::<Vec<_>>();
+        let tail = if hang {
+            futures::stream::pending().left_stream()
+        } else {
+            futures::stream::empty().right_stream()
+        };
+        futures::stream::iter(chunks).chain(tail).eventsource()
+    }
+
+    fn delta(text: &str) -> (&'static str, Value) {
+        (
+            "content_block_delta",
+            json!({ "type": "content_block_delta", "index": 0,
+                "delta": { "type": "text_delta", "text": text } }),
+        )
+    }
+
+    #[tokio::test]
+    async fn merge_sse_reads_both_shapes() {
+        let raw = [
+            ("completion", json!({ "completion": "Hello" })),
+            ("completion", json!({ "completion": " world" })),
+        ];
+        let (text, truncated) = merge_sse(upstream(&raw, false), None, 0).await;
+        assert_eq!((text.as_str(), truncated), ("Hello world", false));
+        let messages = [
+            ("message_start", json!({ "type": "message_start" })),
+            delta("Hello"),
+            delta(" world"),
+            ("message_stop", json!({ "type": "message_stop" })),
+        ];
+        let (text, truncated) = merge_sse(upstream(&messages, false), None, 0).await;
+        assert_eq!((text.as_str(), truncated), ("Hello world", false));
+    }
+
+    #[tokio::test]
+    async fn merge_sse_returns_partial_at_soft_timeout() {
+        let slow = upstream(&[delta("Once upon")], true);
+        let (text, truncated) = merge_sse(slow, Some(Duration::from_millis(50)), 0).await;
+        assert_eq!((text.as_str(), truncated), ("Once upon", true));
+    }
+
+    #[tokio::test]
+    async fn merge_sse_truncates_at_max_bytes() {
+        let long = upstream(&[delta("héllo "), delta("world"), delta(" again")], false);
+        let (text, truncated) = merge_sse(long, None, 2).await;
+        // never cut inside a char
+        assert_eq!((text.as_str(), truncated), ("h", true));
+        let short = upstream(&[delta("hi")], false);
+        assert_eq!(merge_sse(short, None, 2).await, ("hi".to_string(), false));
+    }
+
+    #[test]
+    fn char_boundary_never_splits_chars() {
+        assert_eq!(char_boundary("héllo", 2), 1);
+        assert_eq!(char_boundary("héllo", 3), 3);
+        assert_eq!(char_boundary("hi", 10), 2);
+    }
+
+    #[test]
+    fn merge_system_skips_non_text_blocks() {
+        assert_eq!(merge_system(json!("plain")), "plain");
+        let sys = json!([
+            { "type": "text", "text": " first " },
+            { "type": "image", "source": {} },
+            { "type": "text", "text": "second", "cache_control": { "type": "ephemeral" } },
+        ]);
+        assert_eq!(merge_system(sys), "first\nsecond");
+        assert_eq!(merge_system(json!(null)), "");
+    }
+
+    #[test]
+    fn emptied_message_keeps_later_turns() {
+        let msgs = vec![
+            Message::new_text(Role::User, "hi"),
+            Message {
+                strip: Some(true),
+                ..Message::new_text(Role::Assistant, "  \n\n  ")
+            },
+            Message::new_blocks(
+                Role::User,
+                vec![ContentBlock::Image {
+                    source: ImageSource {
+                        type_: "base64".to_string(),
+                        media_type: "image/png".to_string(),
+                        data: "iVBORw0KGgo=".to_string(),
+                    },
+                }],
+            ),
+            Message::new_text(Role::Assistant, "hello"),
+            Message::new_text(Role::User, "bye"),
+        ];
+        let merged = state(Config::default())
+            .merge_messages(msgs, "sys".to_string(), false, false)
+            .unwrap();
+        assert_eq!(
+            merged.paste,
+            "sys\n\nHuman: hi\n\nAssistant: hello\n\nHuman: bye"
+        );
+        assert_eq!(merged.images.len(), 1);
+    }
+
+    /// Config with enough pad tokens to pad every request
+    fn padded_config() -> Config {
+        let mut config = Config::default();
+        config.padtxt_len = 100;
+        config.pad_tokens = (0..200).map(|i| format!("tok{i}")).collect();
+        config
+    }
+
+    /// Request with a system prompt and a long user turn, streaming or not
+    fn chat(stream: bool) -> ClientRequestBody {
+        ClientRequestBody {
+            stream,
+            ..request("Be nice.", &"Tell me a long story. ".repeat(10))
+        }
+    }
+
+    #[test]
+    fn stream_padding_follows_flag() {
+        let mut config = padded_config();
+        config.padtxt_stream = false;
+        let off = state(config.clone());
+        assert!(off.transform(chat(true)).unwrap().padding.is_none());
+        assert!(off.transform(chat(false)).unwrap().padding.is_some());
+        config.padtxt_stream = true;
+        let on = state(config);
+        assert!(on.transform(chat(true)).unwrap().padding.is_some());
+    }
+
+    #[test]
+    fn utility_request_skips_padding_and_custom_prompt() {
+        let mut config = padded_config();
+        config.utility_fast_path = true;
+        config.custom_prompt = "Stay in character.".to_string();
+        let fast = state(config);
+        let utility = fast
+            .transform(request("Reply with one word.", "Outfit?"))
+            .unwrap();
+        assert!(utility.padding.is_none());
+        assert!(!utility.prompt.contains("Stay in character."));
+        // a real conversation is still padded
+        let full = fast.transform(chat(false)).unwrap();
+        assert!(full.padding.is_some());
+        assert!(full.prompt.contains("Stay in character."));
+        // without the fast path nothing is skipped
+        let slow = state(padded_config())
+            .transform(request("Reply with one word.", "Outfit?"))
+            .unwrap();
+        assert!(slow.padding.is_some());
+    }
+
+    #[test]
+    fn old_history_kept_in_attachment() {
+        let turns = || {
+            ["u1", "a1", "u2", "a2", "u3"]
+                .iter()
+                .enumerate()
+                .map(|(i, t)| {
+                    let role = if i % 2 == 0 {
+                        Role::User
+                    } else {
+                        Role::Assistant
+                    };
+                    Message::new_text(role, *t)
+                })
+                .collect::<Vec<_>>()
+        };
+        let mut config = Config::default();
+        config.history_as_attachment_threshold = 2;
+        let merged = state(config)
+            .merge_messages(turns(), "sys".to_string(), false, false)
+            .unwrap();
+        assert_eq!(
+            merged.paste,
+            "sys\n\nHuman: u1\n\nAssistant: a1\n\nHuman: u2"
+        );
+        assert_eq!(merged.prompt, "Assistant: a2\n\nHuman: u3");
+        // disabled, the whole history is in the attachment
+        let merged = state(Config::default())
+            .merge_messages(turns(), "sys".to_string(), false, false)
+            .unwrap();
+        assert!(merged.paste.ends_with("Human: u3"));
+        assert!(merged.prompt.is_empty());
+    }
+
+    /// Outgoing body of the request for the model, as sent upstream
+    fn sent(state: &AppState, model: &str, stream: bool) -> Value {
+        let body = state
+            .transform(ClientRequestBody {
+                model: model.to_string(),
+                ..chat(stream)
+            })
+            .unwrap();
+        serde_json::to_value(&body).unwrap()
+    }
+
+    #[test]
+    fn rendering_and_attachment_follow_model() {
+        let mut config = Config::default();
+        config.inline_prompt_models = vec!["claude-3-opus".to_string()];
+        let by_model = state(config.clone());
+        let inline = sent(&by_model, "claude-3-opus-20240229", false);
+        assert_eq!(inline["attachments"], json!([]));
+        assert!(
+            inline["prompt"]
+                .as_str()
+                .unwrap()
+                .contains("Tell me a long story.")
+        );
+        let attached = sent(&by_model, "claude-3-5-sonnet-20241022", false);
+        assert_eq!(attached["attachments"].as_array().unwrap().len(), 1);
+        assert!(!attached["prompt"].as_str().unwrap().contains("Tell me"));
+
+        // (mode, stream, expected)
+        let cases = [
+            (RenderingMode::Auto, false, "raw"),
+            (RenderingMode::Auto, true, "messages"),
+            (RenderingMode::Raw, false, "raw"),
+            (RenderingMode::Raw, true, "messages"),
+            (RenderingMode::Messages, false, "messages"),
+            (RenderingMode::Messages, true, "messages"),
+        ];
+        for (mode, stream, expected) in cases {
+            config.rendering_mode = mode;
+            let body = sent(&state(config.clone()), "claude", stream);
+            assert_eq!(
+                body["rendering_mode"], expected,
+                "{mode:?}, stream {stream}"
+            );
+        }
+    }
+
+    #[test]
+    fn default_max_tokens_in_outgoing_body() {
+        let mut config = Config::default();
+        config.default_max_tokens = 1234;
+        let state = state(config);
+        let body = sent(&state, "claude", false);
+        assert_eq!(body["max_tokens_to_sample"], 1234);
+        let explicit = state
+            .transform(ClientRequestBody {
+                max_tokens: Some(500),
+                ..chat(false)
+            })
+            .unwrap();
+        assert_eq!(explicit.max_tokens_to_sample, 500);
+    }
+
+    #[test]
+    fn missing_pad_tokens_fall_back_to_custom_prompt() {
+        let mut config = Config::default();
+        config.padtxt_file = "missing_padtxt.txt".to_string();
+        config.padtxt_len = 100;
+        config.custom_prompt = "Stay in character.".to_string();
+        let body = state(config).transform(chat(false)).unwrap();
+        assert!(body.padding.is_none());
+        assert!(body.prompt.contains("Stay in character."));
+        let paste = serde_json::to_value(&body).unwrap()["attachments"][0]["extracted_content"]
+            .as_str()
+            .unwrap()
+            .to_string();
+        assert!(paste.starts_with("Be nice."));
+    }
+
+    #[test]
+    fn attachment_uses_configured_file() {
+        let mut config = Config::default();
+        config.attachment_file_name = "context.md".to_string();
+        config.attachment_file_type = "md".to_string();
+        let body = sent(&state(config), "claude", false);
+        let attachment = &body["attachments"][0];
+        assert_eq!(attachment["file_name"], "context.md");
+        assert_eq!(attachment["file_type"], "md");
+        let content = attachment["extracted_content"].as_str().unwrap();
+        assert_eq!(attachment["file_size"], content.len());
+    }
+
+    #[test]
+    fn zero_or_absent_max_tokens_get_default() {
+        let mut config = Config::default();
+        config.default_max_tokens = 4096;
+        let state = state(config);
+        let sent = |max_tokens| {
+            state
+                .transform(ClientRequestBody {
+                    max_tokens,
+                    ..chat(false)
+                })
+                .unwrap()
+                .max_tokens_to_sample
+        };
+        assert_eq!(sent(None), 4096);
+        assert_eq!(sent(Some(0)), 4096);
+        assert_eq!(sent(Some(1)), 1);
+    }
 }
@@ -155,6 +155,27 @@ pub struct Message {
     /// Content of the message (either string or array of content blocks)
     #[serde(flatten)]
     pub content: MessageContent,
+    /// Drop the message from the prompt
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default)]
+    pub discard: Option<bool>,
+    /// Trim every line of the message and drop blank lines
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default)]
+    pub strip: Option<bool>,
+    /// Merge the message into the previous one regardless of role
+    /// A system message is folded into the next user turn instead
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default)]
+    pub merged: Option<bool>,
+    /// Jailbreak message, relocated according to `jailbreak_position`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default)]
+    pub jailbreak: Option<bool>,
+    /// Main prompt, moved to the front of the messages
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default)]
+    pub main: Option<bool>,
 }
 
 /// Role of a message sender
@@ -164,6 +185,8 @@ pub enum Role {
     User,
     #[default]
     Assistant,
+    /// System text placed among the messages, sent without a role prefix
+    System,
 }
 
 /// Content of a message
@@ -197,7 +220,8 @@ pub enum ContentBlock {
     #[serde(rename = "tool_result")]
     ToolResult {
         tool_use_id: String,
-        content: String,
+        /// Plain text or a list of content blocks
+        content: serde_json::Value,
     },
 }
 
@@ -306,6 +330,11 @@ impl Message {
             content: MessageContent::Text {
                 content: text.into(),
             },
+            discard: None,
+            strip: None,
+            merged: None,
+            jailbreak: None,
+            main: None,
         }
     }
 
@@ -314,6 +343,11 @@ impl Message {
         Self {
             role,
             content: MessageContent::Blocks { content: blocks },
+            discard: None,
+            strip: None,
+            merged: None,
+            jailbreak: None,
+            main: None,
         }
     }
 }
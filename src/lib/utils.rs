@@ -1,5 +1,5 @@
-use std::path::PathBuf;
-use tracing::error;
+use std::path::{Path, PathBuf};
+use tracing::{error, warn};
 
 use crate::{config::CONFIG_NAME, error::ClewdrError};
 
@@ -16,27 +16,66 @@ pub fn config_dir() -> Result<PathBuf, ClewdrError> {
         .parent()
         .ok_or_else(|| ClewdrError::PathNotFound("exec dir".to_string()))?
         .to_path_buf();
-    return Ok(exec_dir);
+    Ok(exec_dir)
 }
 
-/// Helper function to print out json
-pub fn print_out_json(json: &impl serde::ser::Serialize, file_name: &str) {
-    let text = serde_json::to_string_pretty(json).unwrap_or_default();
-    print_out_text(&text, file_name);
+/// Directory under `log` holding one subdirectory per request
+const REQUEST_LOG_DIR: &str = "requests";
+
+/// Create the log directory of a new request, named so that names sort by time
+/// The oldest directories beyond `keep` are removed, 0 keeps all of them
+pub fn new_request_log_dir(keep: usize) -> Option<String> {
+    let dir = config_dir().ok()?.join("log").join(REQUEST_LOG_DIR);
+    let name = format!(
+        "{}-{}",
+        chrono::Local::now().format("%Y%m%d-%H%M%S%.3f"),
+        &uuid::Uuid::new_v4().simple().to_string()[..8]
+    );
+    if let Err(e) = std::fs::create_dir_all(dir.join(&name)) {
+        error!("Failed to create request log dir: {}", e);
+        return None;
+    }
+    if keep > 0 {
+        prune_request_logs(&dir, keep);
+    }
+    Some(format!("{}/{}", REQUEST_LOG_DIR, name))
+}
+
+/// Remove the oldest request log directories, keeping the newest `keep`
+fn prune_request_logs(dir: &Path, keep: usize) {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return;
+    };
+    let mut names = entries
+        .filter_map(|e| e.ok())
+        .filter(|e| e.path().is_dir())
+        .map(|e| e.file_name())
+        .collect::<Vec<_>>();
+    names.sort();
+    let excess = names.len().saturating_sub(keep);
+    for name in &names[..excess] {
+        if let Err(e) = std::fs::remove_dir_all(dir.join(name)) {
+            warn!("Failed to remove old request log: {}", e);
+        }
+    }
 }
 
-/// Helper function to print out text
-pub fn print_out_text(text: &str, file_name: &str) {
+/// Helper function to print out text, into the subdirectory of `log` if given
+pub fn print_out_text(subdir: Option<&str>, text: &str, file_name: &str) {
     let Ok(dir) = config_dir() else {
         error!("No config found in cwd or exec dir");
         return;
     };
     let log_dir = dir.join("log");
-    if !log_dir.exists() {
-        if let Err(e) = std::fs::create_dir_all(&log_dir) {
-            error!("Failed to create log dir: {}\n", e);
-            return;
-        }
+    let log_dir = match subdir {
+        Some(subdir) => log_dir.join(subdir),
+        None => log_dir,
+    };
+    if !log_dir.exists()
+        && let Err(e) = std::fs::create_dir_all(&log_dir)
+    {
+        error!("Failed to create log dir: {}\n", e);
+        return;
     }
     let file_name = log_dir.join(file_name);
     let Ok(mut file) = std::fs::File::options()
@@ -53,5 +92,39 @@ pub fn print_out_text(text: &str, file_name: &str) {
     }
 }
 
+/// 64-bit FNV-1a hash, stable across builds and restarts
+pub fn fnv1a(bytes: impl IntoIterator<Item = u8>) -> u64 {
+    bytes.into_iter().fold(0xcbf29ce484222325, |h, b| {
+        (h ^ b as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+/// Compare two strings in time independent of where they differ
+/// Used for secrets, so a wrong guess leaks nothing about the right one
+pub fn ct_eq(a: &str, b: &str) -> bool {
+    let (a, b) = (a.as_bytes(), b.as_bytes());
+    let diff = (0..a.len().max(b.len())).fold(a.len() ^ b.len(), |diff, i| {
+        let x = a.get(i).copied().unwrap_or_default();
+        let y = b.get(i).copied().unwrap_or_default();
+        diff | (x ^ y) as usize
+    });
+    std::hint::black_box(diff) == 0
+}
+
 /// Timezone for the API
 pub const TIME_ZONE: &str = "America/New_York";
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ct_eq_compares_whole_strings() {
+        assert!(ct_eq("secret", "secret"));
+        assert!(ct_eq("", ""));
+        assert!(!ct_eq("secret", "secreT"));
+        assert!(!ct_eq("secret", "secret2"));
+        assert!(!ct_eq("secret", "secre"));
+        assert!(!ct_eq("secret", ""));
+    }
+}
@@ -1,13 +1,24 @@
 use clap::Parser;
 use clewdr::{
-    self, BANNER, config::Config, cookie::CookieManager, error::ClewdrError, state::AppState,
+    self, BANNER,
+    config::Config,
+    cookie::{CookieManager, PoolStatus},
+    error::ClewdrError,
+    logging::{LogFormat, request_filter},
+    state::AppState,
     utils::config_dir,
 };
 use colored::Colorize;
 use const_format::formatc;
-use tokio::{spawn, sync::mpsc};
+use std::time::Duration;
+use tokio::{
+    select, signal, spawn,
+    sync::{mpsc, watch},
+    time::{sleep, timeout},
+};
+use tracing::Level;
 use tracing_subscriber::{
-    Registry,
+    Layer, Registry,
     fmt::{self, time::ChronoLocal},
     layer::SubscriberExt,
 };
@@ -31,17 +42,38 @@ async fn main() -> Result<(), ClewdrError> {
     let file_appender = tracing_appender::rolling::daily(log_dir, "rolling.log");
     let (file_writer, _guard) = tracing_appender::non_blocking(file_appender);
 
-    let subscriber = Registry::default()
-        .with(
-            fmt::Layer::default()
-                .with_writer(file_writer)
-                .with_timer(timer.clone()),
-        )
-        .with(
-            fmt::Layer::default()
-                .with_writer(std::io::stdout)
-                .with_timer(timer),
-        );
+    // requests with the debug header are logged at all levels
+    let level = if cfg!(debug_assertions) {
+        Level::DEBUG
+    } else {
+        Level::INFO
+    };
+    let filter = request_filter(level);
+    let layer = match LogFormat::from_env() {
+        LogFormat::Text => fmt::Layer::default()
+            .with_writer(file_writer)
+            .with_timer(timer.clone())
+            .and_then(
+                fmt::Layer::default()
+                    .with_writer(std::io::stdout)
+                    .with_timer(timer),
+            )
+            .boxed(),
+        LogFormat::Json => fmt::Layer::default()
+            .json()
+            .with_current_span(true)
+            .with_writer(file_writer)
+            .with_timer(timer.clone())
+            .and_then(
+                fmt::Layer::default()
+                    .json()
+                    .with_current_span(true)
+                    .with_writer(std::io::stdout)
+                    .with_timer(timer),
+            )
+            .boxed(),
+    };
+    let subscriber = Registry::default().with(layer.with_filter(filter));
 
     tracing::subscriber::set_global_default(subscriber).expect("unable to set global subscriber");
 
@@ -59,20 +91,90 @@ async fn main() -> Result<(), ClewdrError> {
     println!("{}", TITLE.blue());
     println!("Listening on {}", config.address().green());
     println!("{}", config);
+    for line in config.cookie_diagnostics() {
+        println!("{}", line);
+    }
+    for line in config.group_summary() {
+        println!("{}", line);
+    }
 
     // initialize the application state
     let (req_tx, req_rx) = mpsc::channel(config.max_connections);
     let (ret_tx, ret_rx) = mpsc::channel(config.max_connections);
     let (submit_tx, submit_rx) = mpsc::channel(config.max_connections);
-    let state = AppState::new(config.clone(), req_tx, ret_tx, submit_tx);
-    let cm = CookieManager::new(config, req_rx, ret_rx, submit_rx);
-    // build axum router
+    let (admin_tx, admin_rx) = mpsc::channel(config.max_connections);
+    let (status_tx, status_rx) = watch::channel(PoolStatus::default());
+    let state = AppState::new(
+        config.clone(),
+        req_tx,
+        ret_tx,
+        submit_tx,
+        admin_tx,
+        status_rx,
+    );
+    let cm = CookieManager::new(config, req_rx, ret_rx, submit_rx, admin_rx, status_tx);
+    spawn(cm.run());
+    let chats = state.clone();
+    // build axum router, after the self-test if enabled
+    let router = match clewdr::router::ready_router(state).await {
+        Ok(router) => router,
+        Err(e) => {
+            println!("{}", e.to_string().red());
+            return Err(e);
+        }
+    };
     // create a TCP listener
-    let addr = state.config.address().to_string();
+    let addr = chats.config.address().to_string();
     let listener = tokio::net::TcpListener::bind(addr).await?;
-    let router = clewdr::router::RouterBuilder::new(state).build();
-    // serve the application
-    spawn(cm.run());
-    axum::serve(listener, router).await?;
+    // serve the application until a shutdown signal
+    // in-flight requests get a grace period to finish and delete their chats
+    let server = axum::serve(listener, router).with_graceful_shutdown(shutdown_signal());
+    select! {
+        res = server => res?,
+        _ = async {
+            shutdown_signal().await;
+            sleep(SHUTDOWN_GRACE).await;
+        } => {
+            println!("{}", "Shutdown grace period over, dropping open requests".yellow());
+        }
+    }
+    // conversations of dropped requests would stay on claude.ai otherwise
+    if timeout(SHUTDOWN_GRACE, chats.delete_open_chats())
+        .await
+        .is_err()
+    {
+        println!("{}", "Timed out deleting open conversations".yellow());
+    }
     Ok(())
 }
+
+/// Time in-flight requests get to finish after a shutdown signal
+const SHUTDOWN_GRACE: Duration = Duration::from_secs(10);
+
+/// Wait for Ctrl+C, or SIGTERM on unix
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        if let Err(e) = signal::ctrl_c().await {
+            eprintln!("Failed to listen for Ctrl+C: {}", e);
+            std::future::pending::<()>().await;
+        }
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        match signal::unix::signal(signal::unix::SignalKind::terminate()) {
+            Ok(mut s) => {
+                s.recv().await;
+            }
+            Err(e) => {
+                eprintln!("Failed to listen for SIGTERM: {}", e);
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+    select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+}